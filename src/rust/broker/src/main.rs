@@ -1,22 +1,65 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use log::{error, info};
+use processor_connection::{ProcessorConfig, ProcessorStats, StalenessCheck};
+use queue::SlowConsumerPolicy;
+use registry::SymbolRegistry;
+use router::Router;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, RwLock};
+use server::ClientConfig;
+use strum::{EnumIter, IntoStaticStr};
+use tokio::sync::RwLock;
+use util::env_or;
 
+mod metrics;
 mod processor_connection;
+mod queue;
+mod registry;
+mod replay;
+mod router;
 mod server;
+mod snapshot;
 mod types;
 mod util;
 
-const CHANNEL_BUFFER_SIZE: usize = 2048;
+/// Default number of recent events kept in memory for clients to catch up on.
+const DEFAULT_REPLAY_BUFFER_SIZE: usize = 10_000;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Default maximum number of events waiting to be sent to a client.
+const DEFAULT_CLIENT_QUEUE_SIZE: usize = 1024;
+
+/// Default maximum number of markets, event types and candlestick periods in a subscription.
+const DEFAULT_MAX_SUBSCRIPTION_SIZE: usize = 1000;
+
+/// Default interval between pings to websocket clients, in milliseconds.
+const DEFAULT_PING_INTERVAL_MS: u64 = 30_000;
+
+/// Default time websocket clients have to answer a ping, in milliseconds.
+const DEFAULT_PONG_TIMEOUT_MS: u64 = 10_000;
+
+/// Default interval between pings to the processor, in milliseconds.
+const DEFAULT_PROCESSOR_PING_INTERVAL_MS: u64 = 30_000;
+
+/// Default time the processor has to answer a ping, in milliseconds.
+const DEFAULT_PROCESSOR_PONG_TIMEOUT_MS: u64 = 10_000;
+
+/// Default time clients have to disconnect when the broker shuts down, in milliseconds.
+const DEFAULT_DRAIN_PERIOD_MS: u64 = 10_000;
+
+/// Default longest delay after which clients are asked to reconnect when the broker shuts down, in
+/// milliseconds.
+const DEFAULT_RECONNECT_JITTER_MS: u64 = 5_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, EnumIter, IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub enum HealthStatus {
     Starting,
     Ok,
     Sick,
     Dead,
+    /// Connected, but the processor is not making progress.
+    Stale,
 }
 
 #[tokio::main]
@@ -29,19 +72,78 @@ async fn main() -> Result<(), ()> {
         .expect("Environment variable PORT is not set.")
         .parse()
         .expect("Environment variable PORT is not a valid port.");
+    let replay_buffer_size = env_or("REPLAY_BUFFER_SIZE", DEFAULT_REPLAY_BUFFER_SIZE);
+    let client_config = ClientConfig {
+        queue_size: env_or("CLIENT_QUEUE_SIZE", DEFAULT_CLIENT_QUEUE_SIZE),
+        slow_consumer_policy: env_or("SLOW_CONSUMER_POLICY", SlowConsumerPolicy::DropOldest),
+        max_subscription_size: env_or("MAX_SUBSCRIPTION_SIZE", DEFAULT_MAX_SUBSCRIPTION_SIZE),
+        // Zero disables pings.
+        ping_interval: Some(env_or("PING_INTERVAL_MS", DEFAULT_PING_INTERVAL_MS))
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis),
+        pong_timeout: Duration::from_millis(env_or("PONG_TIMEOUT_MS", DEFAULT_PONG_TIMEOUT_MS)),
+        // Zero, the default, lets clients stay connected without subscribing.
+        idle_timeout: Some(env_or("IDLE_TIMEOUT_MS", 0))
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis),
+        // Zero disables compression.
+        compression_level: Some(env_or("WS_COMPRESSION_LEVEL", 0))
+            .filter(|level| *level > 0)
+            .map(|level| level.min(9)),
+        drain_period: Duration::from_millis(env_or("DRAIN_PERIOD_MS", DEFAULT_DRAIN_PERIOD_MS)),
+        reconnect_jitter: Duration::from_millis(env_or(
+            "RECONNECT_JITTER_MS",
+            DEFAULT_RECONNECT_JITTER_MS,
+        )),
+    };
 
-    let (tx, _) = broadcast::channel(CHANNEL_BUFFER_SIZE);
-    let tx2 = tx.clone();
+    let processor_config = ProcessorConfig {
+        resume_param: std::env::var("PROCESSOR_RESUME_PARAM").ok(),
+        // Zero disables pings.
+        ping_interval: Some(env_or(
+            "PROCESSOR_PING_INTERVAL_MS",
+            DEFAULT_PROCESSOR_PING_INTERVAL_MS,
+        ))
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis),
+        pong_timeout: Duration::from_millis(env_or(
+            "PROCESSOR_PONG_TIMEOUT_MS",
+            DEFAULT_PROCESSOR_PONG_TIMEOUT_MS,
+        )),
+        // Zero disables the staleness check.
+        staleness_check: Some(env_or("STALE_AFTER_MS", 0))
+            .filter(|ms| *ms > 0)
+            .map(|ms| StalenessCheck {
+                threshold: Duration::from_millis(ms),
+                reconnect: env_or("STALE_RECONNECT", false),
+            }),
+    };
+
+    let symbols = match std::env::var("MARKET_SYMBOLS_FILE") {
+        Ok(path) => SymbolRegistry::load(&path).expect("Could not load MARKET_SYMBOLS_FILE."),
+        Err(_) => SymbolRegistry::default(),
+    };
+
+    let router = Arc::new(Router::new(replay_buffer_size, symbols));
 
     let processor_connection_health = Arc::new(RwLock::new(HealthStatus::Starting));
+    let processor_stats = Arc::new(ProcessorStats::default());
 
     let processor_connection = tokio::spawn(processor_connection::start(
         processor_url,
-        tx2,
+        processor_config,
+        router.clone(),
         processor_connection_health.clone(),
+        processor_stats.clone(),
     ));
 
-    let mut sse_server = tokio::spawn(server::server(tx, port, processor_connection_health));
+    let mut sse_server = tokio::spawn(server::server(
+        router,
+        port,
+        processor_connection_health,
+        processor_stats,
+        client_config,
+    ));
 
     tokio::select! {
         _ = processor_connection => {
@@ -0,0 +1,289 @@
+use std::sync::{Arc, OnceLock};
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use strum::IntoEnumIterator;
+
+use crate::{router::DIMENSIONS, types::SequencedEvent, HealthStatus};
+
+/// Metrics of the broker, exported in the Prometheus text format on `/metrics`.
+///
+/// The gauges of the processor connection status are set when the metrics are rendered, while the
+/// subscription counts are kept up to date as subscriptions change.
+pub struct Metrics {
+    registry: Registry,
+    processor_status: IntGaugeVec,
+    pub processor_reconnects: IntCounter,
+    pub events_received: IntCounterVec,
+    clients: IntGaugeVec,
+    events_delivered: IntCounterVec,
+    bytes_sent: IntCounterVec,
+    bytes_saved: IntCounterVec,
+    lagged: IntCounterVec,
+    events_dropped: IntCounterVec,
+    send_latency: HistogramVec,
+    subscriptions: IntGaugeVec,
+}
+
+/// Metrics of the clients connected with one transport, e.g. `ws` or `sse`.
+pub struct TransportMetrics {
+    clients: IntGauge,
+    events_delivered: IntCounter,
+    bytes_sent: IntCounter,
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    bytes_saved: IntCounter,
+    lagged: IntCounter,
+    events_dropped: IntCounter,
+    send_latency: Histogram,
+}
+
+/// Keeps a client counted as connected until it is dropped.
+pub struct ConnectedClient(Arc<TransportMetrics>);
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.0.clients.dec();
+    }
+}
+
+#[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+impl TransportMetrics {
+    /// Counts a client as connected until the returned value is dropped.
+    pub fn connect(self: &Arc<Self>) -> ConnectedClient {
+        self.clients.inc();
+        ConnectedClient(self.clone())
+    }
+
+    /// Records that a message of the given size was sent, containing the given events.
+    pub fn sent(&self, bytes: usize, events: &[Arc<SequencedEvent>]) {
+        self.bytes_sent.inc_by(bytes as u64);
+        self.events_delivered.inc_by(events.len() as u64);
+        for event in events {
+            self.send_latency
+                .observe(event.received_at.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Records that a message of the given size was compressed to the given size.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub fn compressed(&self, bytes: usize, compressed_bytes: usize) {
+        // Counters can't decrease, and only the smallest messages grow.
+        self.bytes_saved
+            .inc_by(bytes.saturating_sub(compressed_bytes) as u64);
+    }
+
+    /// Records that the given number of events were dropped because a client was too slow.
+    pub fn lagged(&self, dropped: u64) {
+        self.lagged.inc();
+        self.events_dropped.inc_by(dropped);
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let processor_status = IntGaugeVec::new(
+            Opts::new(
+                "broker_processor_status",
+                "Status of the connection to the processor, 1 for the current one.",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let processor_reconnects = IntCounter::new(
+            "broker_processor_reconnects_total",
+            "Reconnections to the processor.",
+        )
+        .unwrap();
+        let events_received = IntCounterVec::new(
+            Opts::new(
+                "broker_events_received_total",
+                "Events received from the processor.",
+            ),
+            &["event_type"],
+        )
+        .unwrap();
+        let clients = IntGaugeVec::new(
+            Opts::new("broker_clients", "Connected clients."),
+            &["transport"],
+        )
+        .unwrap();
+        let events_delivered = IntCounterVec::new(
+            Opts::new("broker_events_delivered_total", "Events sent to clients."),
+            &["transport"],
+        )
+        .unwrap();
+        let bytes_sent = IntCounterVec::new(
+            Opts::new(
+                "broker_bytes_sent_total",
+                "Bytes of the messages sent to clients.",
+            ),
+            &["transport"],
+        )
+        .unwrap();
+        let bytes_saved = IntCounterVec::new(
+            Opts::new(
+                "broker_bytes_saved_total",
+                "Bytes saved by compressing the messages sent to clients.",
+            ),
+            &["transport"],
+        )
+        .unwrap();
+        let lagged = IntCounterVec::new(
+            Opts::new(
+                "broker_lagged_total",
+                "Times events were dropped because clients were too slow.",
+            ),
+            &["transport"],
+        )
+        .unwrap();
+        let events_dropped = IntCounterVec::new(
+            Opts::new(
+                "broker_events_dropped_total",
+                "Events dropped because clients were too slow.",
+            ),
+            &["transport"],
+        )
+        .unwrap();
+        let send_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "broker_send_latency_seconds",
+                "Time between the broker receiving an event and sending it to a client.",
+            )
+            .buckets(exponential_buckets(0.0005, 2.0, 16).unwrap()),
+            &["transport"],
+        )
+        .unwrap();
+        let subscriptions = IntGaugeVec::new(
+            Opts::new(
+                "broker_subscriptions",
+                "Client subscriptions using each dimension.",
+            ),
+            &["dimension"],
+        )
+        .unwrap();
+        // Exported even while no subscription uses them.
+        for dimension in DIMENSIONS {
+            subscriptions.with_label_values(&[dimension]);
+        }
+
+        registry
+            .register(Box::new(processor_status.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(processor_reconnects.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(events_received.clone()))
+            .unwrap();
+        registry.register(Box::new(clients.clone())).unwrap();
+        registry
+            .register(Box::new(events_delivered.clone()))
+            .unwrap();
+        registry.register(Box::new(bytes_sent.clone())).unwrap();
+        registry.register(Box::new(bytes_saved.clone())).unwrap();
+        registry.register(Box::new(lagged.clone())).unwrap();
+        registry.register(Box::new(events_dropped.clone())).unwrap();
+        registry.register(Box::new(send_latency.clone())).unwrap();
+        registry.register(Box::new(subscriptions.clone())).unwrap();
+
+        Self {
+            registry,
+            processor_status,
+            processor_reconnects,
+            events_received,
+            clients,
+            events_delivered,
+            bytes_sent,
+            bytes_saved,
+            lagged,
+            events_dropped,
+            send_latency,
+            subscriptions,
+        }
+    }
+
+    /// Returns the metrics of the clients connected with the given transport.
+    #[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+    pub fn transport(&self, transport: &str) -> Arc<TransportMetrics> {
+        let labels = [transport];
+        Arc::new(TransportMetrics {
+            clients: self.clients.with_label_values(&labels),
+            events_delivered: self.events_delivered.with_label_values(&labels),
+            bytes_sent: self.bytes_sent.with_label_values(&labels),
+            bytes_saved: self.bytes_saved.with_label_values(&labels),
+            lagged: self.lagged.with_label_values(&labels),
+            events_dropped: self.events_dropped.with_label_values(&labels),
+            send_latency: self.send_latency.with_label_values(&labels),
+        })
+    }
+
+    /// Records that a subscription using the given dimensions was added, or removed if `change` is
+    /// negative.
+    pub fn subscribed(&self, dimensions: &[&str], change: i64) {
+        for dimension in dimensions {
+            self.subscriptions
+                .with_label_values(&[dimension])
+                .add(change);
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format, after setting the gauges that mirror
+    /// the given processor connection status.
+    pub fn render(&self, health: &HealthStatus) -> Result<String, prometheus::Error> {
+        for status in HealthStatus::iter() {
+            let name: &str = (&status).into();
+            let current = std::mem::discriminant(&status) == std::mem::discriminant(health);
+            self.processor_status
+                .with_label_values(&[name])
+                .set(current.into());
+        }
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer).unwrap())
+    }
+}
+
+/// The metrics of the broker.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.events_received.with_label_values(&["Swap"]).inc();
+        let ws = metrics.transport("ws");
+        let client = ws.connect();
+        ws.lagged(3);
+        metrics.subscribed(&["all", "markets"], 1);
+        metrics.subscribed(&["all", "markets"], 1);
+        metrics.subscribed(&["all", "arena"], 1);
+        metrics.subscribed(&["all", "arena"], -1);
+
+        let text = metrics.render(&HealthStatus::Sick).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&r#"broker_processor_status{status="sick"} 1"#));
+        assert!(lines.contains(&r#"broker_processor_status{status="ok"} 0"#));
+        assert!(lines.contains(&r#"broker_events_received_total{event_type="Swap"} 1"#));
+        assert!(lines.contains(&r#"broker_clients{transport="ws"} 1"#));
+        assert!(lines.contains(&r#"broker_lagged_total{transport="ws"} 1"#));
+        assert!(lines.contains(&r#"broker_events_dropped_total{transport="ws"} 3"#));
+        assert!(lines.contains(&r#"broker_subscriptions{dimension="all"} 2"#));
+        assert!(lines.contains(&r#"broker_subscriptions{dimension="markets"} 2"#));
+        assert!(lines.contains(&r#"broker_subscriptions{dimension="arena"} 0"#));
+        assert!(lines.contains(&r#"broker_subscriptions{dimension="filters"} 0"#));
+
+        drop(client);
+        let text = metrics.render(&HealthStatus::Ok).unwrap();
+        assert!(text.contains(r#"broker_clients{transport="ws"} 0"#));
+        assert!(text.contains(r#"broker_processor_status{status="ok"} 1"#));
+    }
+}
@@ -1,23 +1,31 @@
 use std::{
-    sync::Arc,
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use futures_util::{SinkExt, StreamExt};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use processor::emojicoin_dot_fun::EmojicoinDbEvent;
-use tokio::sync::{broadcast::Sender, RwLock};
+use tokio::sync::{Notify, RwLock};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 
-use crate::HealthStatus;
+use crate::{
+    metrics::metrics,
+    router::Router,
+    util::{get_event_name, get_transaction_version},
+    HealthStatus,
+};
 
 /// Number of retries before giving up and exiting.
 const CONNECTION_RETRIES: u64 = 10;
 
 /// Amount of seconds after which a connection is considered successful.
 ///
-/// If a connection lasts at least this amount, and the connection did not return
-/// [`ConnectionError::ConnectionImpossible`], the number of retries will be reset to 0.
+/// If a connection lasts at least this amount, and returned [`ConnectionError::ConnectionLost`],
+/// the number of retries will be reset to 0.
 const RETRY_THRESHOLD: Duration = Duration::from_secs(10);
 
 /// Number of seconds to wait before reconnecting on the first retry.
@@ -26,21 +34,206 @@ const INSTANT_RECONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
 /// Number of seconds to wait before reconnecting on all but the first retry.
 const DELAYED_RECONNECTION_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// How often the processor is checked for staleness, at most.
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings of the connection to the processor.
+#[derive(Debug, Clone)]
+pub struct ProcessorConfig {
+    /// Query parameter used to resume from a transaction version after reconnecting, if any.
+    pub resume_param: Option<String>,
+    /// How often the processor is pinged, if it is.
+    pub ping_interval: Option<Duration>,
+    /// How long the processor has to answer a ping before the connection is considered lost.
+    pub pong_timeout: Duration,
+    /// When to consider that the processor is stuck, if ever.
+    pub staleness_check: Option<StalenessCheck>,
+}
+
+/// When to consider that the processor is stuck.
+#[derive(Debug, Clone, Copy)]
+pub struct StalenessCheck {
+    /// How long the processor can go without sending an event of a newer transaction version.
+    pub threshold: Duration,
+    /// Whether to reconnect to the processor once it is stale.
+    pub reconnect: bool,
+}
+
+/// What was last received from the processor.
+#[derive(Debug, Default)]
+struct Progress {
+    /// When the current connection was established.
+    connected_at: Option<Instant>,
+    /// When the last event was received.
+    last_event_at: Option<Instant>,
+    /// Highest transaction version received.
+    transaction_version: Option<i64>,
+    /// When the highest transaction version was first received.
+    advanced_at: Option<Instant>,
+}
+
+impl Progress {
+    fn record(&mut self, transaction_version: i64, now: Instant) {
+        self.last_event_at = Some(now);
+        if self.transaction_version < Some(transaction_version) {
+            self.transaction_version = Some(transaction_version);
+            self.advanced_at = Some(now);
+        }
+    }
+
+    /// Returns true if no event of a newer transaction version was received within the threshold,
+    /// which includes not receiving any event at all.
+    ///
+    /// Only time spent connected counts, so that the processor isn't stale right after a
+    /// reconnection.
+    fn is_stale(&self, threshold: Duration, now: Instant) -> bool {
+        let Some(connected_at) = self.connected_at else {
+            return false;
+        };
+        let since = self
+            .advanced_at
+            .map_or(connected_at, |at| at.max(connected_at));
+        now.duration_since(since) > threshold
+    }
+}
+
+/// Progress of the connection to the processor, reported on `/health`.
+pub struct ProcessorStats {
+    progress: Mutex<Progress>,
+    reconnects: AtomicU64,
+    retries_left: AtomicU64,
+}
+
+impl Default for ProcessorStats {
+    fn default() -> Self {
+        Self {
+            progress: Mutex::new(Progress::default()),
+            reconnects: AtomicU64::new(0),
+            retries_left: AtomicU64::new(CONNECTION_RETRIES),
+        }
+    }
+}
+
+impl ProcessorStats {
+    /// Time since the last event was received, if one was.
+    pub fn since_last_event(&self) -> Option<Duration> {
+        let progress = self.progress.lock().unwrap();
+        progress.last_event_at.map(|at| at.elapsed())
+    }
+
+    /// Highest transaction version received, and the time since it was first received.
+    pub fn transaction_version(&self) -> Option<(i64, Duration)> {
+        let progress = self.progress.lock().unwrap();
+        progress
+            .transaction_version
+            .zip(progress.advanced_at.map(|at| at.elapsed()))
+    }
+
+    /// Number of reconnections to the processor.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Number of reconnections left before giving up if they keep failing.
+    pub fn retries_left(&self) -> u64 {
+        self.retries_left.load(Ordering::Relaxed)
+    }
+}
+
+/// Position in the processor's event stream up to which events have been forwarded to clients.
+///
+/// A single transaction can emit several events, so the number of events already forwarded for the
+/// last transaction version is tracked as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StreamPosition {
+    transaction_version: i64,
+    events_at_version: usize,
+}
+
+impl StreamPosition {
+    /// Records that an event emitted at the given transaction version was forwarded.
+    fn record(position: &mut Option<StreamPosition>, transaction_version: i64) {
+        match position {
+            Some(p) if p.transaction_version == transaction_version => p.events_at_version += 1,
+            _ => {
+                *position = Some(StreamPosition {
+                    transaction_version,
+                    events_at_version: 1,
+                })
+            }
+        }
+    }
+
+    /// Returns true if events emitted between this position and the given transaction version may
+    /// have been missed, i.e. if the given version is neither this one nor the next one.
+    ///
+    /// Emojicoin events are only emitted by some transactions, so this is usually true even when
+    /// nothing was missed.
+    fn has_gap_before(&self, transaction_version: i64) -> bool {
+        transaction_version > self.transaction_version + 1
+    }
+
+    /// Returns true if an event replayed by the processor at the given transaction version was
+    /// already forwarded before the reconnection.
+    ///
+    /// `resume_point` is consumed as replayed events are skipped, and cleared once the first new
+    /// event is seen.
+    fn is_replayed(resume_point: &mut Option<StreamPosition>, transaction_version: i64) -> bool {
+        match resume_point {
+            Some(p) if transaction_version < p.transaction_version => true,
+            Some(p) if transaction_version == p.transaction_version && p.events_at_version > 0 => {
+                p.events_at_version -= 1;
+                true
+            }
+            _ => {
+                *resume_point = None;
+                false
+            }
+        }
+    }
+}
+
+/// Appends the resume query parameter to the processor URL.
+fn resume_url(processor_url: &str, resume_param: &str, transaction_version: i64) -> String {
+    let separator = if processor_url.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!("{processor_url}{separator}{resume_param}={transaction_version}")
+}
+
 enum ConnectionError {
     /// Could not connect to the processor at all.
     ConnectionImpossible,
     /// Connection to the processor established but lost.
     ConnectionLost,
+    /// Connection to the processor established, but dropped because the processor was stale.
+    ///
+    /// Reconnecting counts as a retry however long the connection lasted, so that a processor that
+    /// stays stale is retried with the usual delays rather than in a loop.
+    Stale,
 }
 
+/// Connects to the processor and forwards its events, reconnecting when the connection is lost.
+///
+/// If the resume parameter is set, reconnections ask the processor to replay events starting at the last
+/// forwarded transaction version by passing it as this query parameter, and events that were
+/// already forwarded are skipped. Otherwise, the range of transaction versions that might have
+/// been missed while disconnected is reported.
 pub async fn start(
     processor_url: String,
-    tx: Sender<EmojicoinDbEvent>,
+    config: ProcessorConfig,
+    router: Arc<Router>,
     processor_connection_health: Arc<RwLock<HealthStatus>>,
+    stats: Arc<ProcessorStats>,
 ) {
     // Number of retries since last successful connection.
     let mut retries = 0;
 
+    // Last forwarded event, kept across reconnections.
+    let position = Arc::new(RwLock::new(None));
+
     // Is this the first iteration of the loop.
     let mut first_time = true;
 
@@ -49,8 +242,11 @@ pub async fn start(
         let start_timestamp = SystemTime::now();
         let res = processor_connection(
             processor_url.clone(),
-            tx.clone(),
+            &config,
+            router.clone(),
             processor_connection_health.clone(),
+            stats.clone(),
+            position.clone(),
         )
         .await;
         let connection_duration = SystemTime::elapsed(&start_timestamp).unwrap();
@@ -63,9 +259,15 @@ pub async fn start(
         if connection_successful {
             retries = 0;
         }
+        stats.retries_left.store(
+            CONNECTION_RETRIES.saturating_sub(retries),
+            Ordering::Relaxed,
+        );
 
-        // If this is the first connection and it was unsuccessful, do not retry.
-        if first_time && !connection_successful {
+        // If this is the first connection and it was unsuccessful, do not retry. A stale processor
+        // was connected to though, so it is retried.
+        let stale = matches!(res, Err(ConnectionError::Stale));
+        if first_time && !connection_successful && !stale {
             break;
         }
 
@@ -85,17 +287,70 @@ pub async fn start(
             error!("No retries left.");
             break;
         }
+        metrics().processor_reconnects.inc();
+        stats.reconnects.fetch_add(1, Ordering::Relaxed);
         retries += 1;
     }
 }
 
-const PING_INTERVAL: u64 = 30;
+/// Marks the connection as stale while the processor isn't making progress, and as ok again once
+/// it is. Other statuses, like sick, are left as they are.
+///
+/// Returns when the processor becomes stale if it should be reconnected to, and never otherwise.
+async fn check_staleness(
+    check: StalenessCheck,
+    processor_connection_health: Arc<RwLock<HealthStatus>>,
+    stats: Arc<ProcessorStats>,
+) {
+    let mut interval = tokio::time::interval(STALENESS_CHECK_INTERVAL.min(check.threshold));
+    loop {
+        interval.tick().await;
+        let stale = stats
+            .progress
+            .lock()
+            .unwrap()
+            .is_stale(check.threshold, Instant::now());
+        let mut health = processor_connection_health.write().await;
+        match *health {
+            HealthStatus::Ok if stale => {
+                warn!(
+                    "The processor did not make progress in {:?}.",
+                    check.threshold
+                );
+                *health = HealthStatus::Stale;
+                if check.reconnect {
+                    return;
+                }
+            }
+            HealthStatus::Stale if !stale => {
+                info!("The processor is making progress again.");
+                *health = HealthStatus::Ok;
+            }
+            _ => {}
+        }
+    }
+}
 
 async fn processor_connection(
     processor_url: String,
-    tx: Sender<EmojicoinDbEvent>,
+    config: &ProcessorConfig,
+    router: Arc<Router>,
     processor_connection_health: Arc<RwLock<HealthStatus>>,
+    stats: Arc<ProcessorStats>,
+    position: Arc<RwLock<Option<StreamPosition>>>,
 ) -> Result<(), ConnectionError> {
+    let last_position = *position.read().await;
+    let processor_url = match (&config.resume_param, last_position) {
+        (Some(resume_param), Some(p)) => {
+            info!(
+                "Resuming from transaction version {}.",
+                p.transaction_version
+            );
+            resume_url(&processor_url, resume_param, p.transaction_version)
+        }
+        _ => processor_url,
+    };
+
     let connection = connect_async(processor_url).await;
     let (mut write, mut read) = if let Ok(connection) = connection {
         connection.0.split()
@@ -106,70 +361,314 @@ async fn processor_connection(
     };
 
     *processor_connection_health.write().await = HealthStatus::Ok;
+    stats.progress.lock().unwrap().connected_at = Some(Instant::now());
+
+    let health_for_check = processor_connection_health.clone();
+    let stats_for_check = stats.clone();
+    let staleness_check = config.staleness_check;
+    let mut stale_handle = tokio::spawn(async move {
+        match staleness_check {
+            Some(check) => check_staleness(check, health_for_check, stats_for_check).await,
+            None => std::future::pending().await,
+        }
+    });
 
-    // Simple heartbeat check to ensure that the `read_handle` below isn't stuck on `read.next()`.
-    let ping_handle = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(PING_INTERVAL));
+    // Notified by the `read_handle` below when a pong is received.
+    let pong = Arc::new(Notify::new());
+    let pong_for_read = pong.clone();
+
+    // Simple heartbeat check to ensure that the `read_handle` below isn't stuck on `read.next()`,
+    // which happens when the connection silently dies.
+    let ping_interval = config.ping_interval;
+    let pong_timeout = config.pong_timeout;
+    let mut ping_handle = tokio::spawn(async move {
+        let Some(ping_interval) = ping_interval else {
+            // Keeps the connection open until the other tasks end.
+            let _write = write;
+            return std::future::pending().await;
+        };
+        let mut interval = tokio::time::interval(ping_interval);
         loop {
             interval.tick().await;
+            // Created before pinging so that the pong can't be missed, while pongs received
+            // earlier don't count.
+            let answered = pong.notified();
             if let Err(e) = SinkExt::send(&mut write, Message::Ping(vec![])).await {
                 error!("Ping failed: {e}");
                 break;
             } else {
                 info!("Ping to processor succeeded!");
             }
+            if tokio::time::timeout(pong_timeout, answered).await.is_err() {
+                error!("The processor did not answer a ping within {pong_timeout:?}.");
+                break;
+            }
         }
     });
 
+    // When resuming, events up to the last position are replayed by the processor and skipped.
+    // Otherwise, the first event received is checked against the last position to report a gap.
+    let (mut resume_point, mut gap_check) = if config.resume_param.is_some() {
+        (last_position, None)
+    } else {
+        (None, last_position)
+    };
+
     let health_for_read = processor_connection_health.clone();
     info!("Connected to the processor.");
     let mut is_sick = false;
-    let read_handle = tokio::spawn(async move {
+    let mut read_handle = tokio::spawn(async move {
         while let Some(msg) = read.next().await {
-            if msg.is_err() {
-                *health_for_read.write().await = HealthStatus::Sick;
-                is_sick = true;
-                error!("Got an error instead of a message: {}", msg.unwrap_err());
-                continue;
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    *health_for_read.write().await = HealthStatus::Sick;
+                    is_sick = true;
+                    error!("Got an error instead of a message: {e}");
+                    continue;
+                }
+            };
+            match msg {
+                Message::Pong(_) => {
+                    pong_for_read.notify_waiters();
+                    continue;
+                }
+                // Answered by `tungstenite`.
+                Message::Ping(_) => continue,
+                _ => {}
             }
-            let msg = msg.unwrap();
-            let msg = msg.to_text();
-            if msg.is_err() {
-                *health_for_read.write().await = HealthStatus::Sick;
-                is_sick = true;
-                error!("Could not convert message to text: {}", msg.unwrap_err());
-                continue;
+            let msg = match msg.to_text() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    *health_for_read.write().await = HealthStatus::Sick;
+                    is_sick = true;
+                    error!("Could not convert message to text: {e}");
+                    continue;
+                }
+            };
+            let db_msg: EmojicoinDbEvent = match serde_json::de::from_str(msg) {
+                Ok(db_msg) => db_msg,
+                Err(e) => {
+                    *health_for_read.write().await = HealthStatus::Sick;
+                    is_sick = true;
+                    error!("Could not parse message: error: {e}, message: {msg}");
+                    continue;
+                }
+            };
+            if is_sick {
+                is_sick = false;
+                *health_for_read.write().await = HealthStatus::Ok;
             }
-            let msg = msg.unwrap();
-            let res: Result<EmojicoinDbEvent, _> = serde_json::de::from_str(msg);
-            if res.is_err() {
-                *health_for_read.write().await = HealthStatus::Sick;
-                is_sick = true;
-                error!(
-                    "Could not parse message: error: {}, message: {}",
-                    res.unwrap_err(),
-                    msg
-                );
+
+            metrics()
+                .events_received
+                .with_label_values(&[get_event_name(&db_msg)])
+                .inc();
+
+            let transaction_version = get_transaction_version(&db_msg);
+            stats
+                .progress
+                .lock()
+                .unwrap()
+                .record(transaction_version, Instant::now());
+            if StreamPosition::is_replayed(&mut resume_point, transaction_version) {
+                info!("Skipping replayed message at version {transaction_version}.");
                 continue;
             }
-            if is_sick {
-                *health_for_read.write().await = HealthStatus::Ok;
+            if let Some(p) = gap_check.take() {
+                if p.has_gap_before(transaction_version) {
+                    debug!(
+                        "Events with transaction versions from {} to {}, at most, may have been \
+                         missed while disconnected from the processor.",
+                        p.transaction_version + 1,
+                        transaction_version - 1
+                    );
+                }
             }
+
             // Log the JSON string.
             info!("Got message from processor: {msg}.");
 
             // And send the actual db model event.
-            let db_msg = res.unwrap();
-            let _ = tx.send(db_msg);
+            router.publish(db_msg);
+            StreamPosition::record(&mut *position.write().await, transaction_version);
         }
     });
 
-    tokio::select! {
-        _ = ping_handle => (),
-        _ = read_handle => (),
-    }
+    let stale = tokio::select! {
+        _ = &mut ping_handle => false,
+        _ = &mut read_handle => false,
+        _ = &mut stale_handle => {
+            info!("Reconnecting to the stale processor.");
+            true
+        }
+    };
+    // The connection is closed once both of its halves are dropped.
+    ping_handle.abort();
+    read_handle.abort();
+    stale_handle.abort();
 
     info!("Connection to the processor terminated.");
     *processor_connection_health.write().await = HealthStatus::Dead;
-    Err(ConnectionError::ConnectionLost)
+    if stale {
+        Err(ConnectionError::Stale)
+    } else {
+        Err(ConnectionError::ConnectionLost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::registry::SymbolRegistry;
+
+    use super::*;
+
+    #[test]
+    fn test_record_position() {
+        let mut position = None;
+        StreamPosition::record(&mut position, 10);
+        StreamPosition::record(&mut position, 10);
+        StreamPosition::record(&mut position, 10);
+        assert_eq!(
+            position,
+            Some(StreamPosition {
+                transaction_version: 10,
+                events_at_version: 3,
+            })
+        );
+        StreamPosition::record(&mut position, 12);
+        assert_eq!(
+            position,
+            Some(StreamPosition {
+                transaction_version: 12,
+                events_at_version: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_skip_replayed_events() {
+        let mut resume_point = Some(StreamPosition {
+            transaction_version: 10,
+            events_at_version: 2,
+        });
+        assert!(StreamPosition::is_replayed(&mut resume_point, 8));
+        assert!(StreamPosition::is_replayed(&mut resume_point, 10));
+        assert!(StreamPosition::is_replayed(&mut resume_point, 10));
+        // Third event of version 10 was never forwarded.
+        assert!(!StreamPosition::is_replayed(&mut resume_point, 10));
+        assert_eq!(resume_point, None);
+        // Once caught up, nothing is skipped anymore.
+        assert!(!StreamPosition::is_replayed(&mut resume_point, 9));
+    }
+
+    #[test]
+    fn test_gap() {
+        let position = StreamPosition {
+            transaction_version: 10,
+            events_at_version: 1,
+        };
+        assert!(!position.has_gap_before(9));
+        assert!(!position.has_gap_before(10));
+        assert!(!position.has_gap_before(11));
+        assert!(position.has_gap_before(12));
+    }
+
+    #[test]
+    fn test_staleness() {
+        let threshold = Duration::from_secs(10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut progress = Progress::default();
+        assert!(!progress.is_stale(threshold, at(100)));
+
+        progress.connected_at = Some(start);
+        assert!(!progress.is_stale(threshold, at(10)));
+        // No event at all.
+        assert!(progress.is_stale(threshold, at(11)));
+
+        progress.record(5, at(8));
+        assert!(!progress.is_stale(threshold, at(18)));
+        // Events keep coming, but the transaction version doesn't advance.
+        progress.record(5, at(15));
+        progress.record(4, at(17));
+        assert!(progress.is_stale(threshold, at(19)));
+        assert_eq!(progress.last_event_at, Some(at(17)));
+        progress.record(6, at(20));
+        assert!(!progress.is_stale(threshold, at(29)));
+
+        // Reconnecting restarts the clock.
+        progress.connected_at = Some(at(40));
+        assert!(!progress.is_stale(threshold, at(45)));
+        assert!(progress.is_stale(threshold, at(51)));
+    }
+
+    #[tokio::test]
+    async fn test_staleness_keeps_other_statuses() {
+        let check = StalenessCheck {
+            threshold: Duration::from_millis(10),
+            reconnect: true,
+        };
+        let stats = Arc::new(ProcessorStats::default());
+        stats.progress.lock().unwrap().connected_at = Some(Instant::now() - Duration::from_secs(1));
+        let health = Arc::new(RwLock::new(HealthStatus::Sick));
+
+        let mut checker = tokio::spawn(check_staleness(check, health.clone(), stats.clone()));
+        let wait = Duration::from_millis(100);
+        assert!(tokio::time::timeout(wait, &mut checker).await.is_err());
+        assert!(matches!(*health.read().await, HealthStatus::Sick));
+
+        // Once no longer sick, the stale processor is reconnected to.
+        *health.write().await = HealthStatus::Ok;
+        assert!(tokio::time::timeout(wait, checker).await.is_ok());
+        assert!(matches!(*health.read().await, HealthStatus::Stale));
+    }
+
+    #[tokio::test]
+    async fn test_missed_pong() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        // Accepts the connection, but never reads from it, so pings are never answered.
+        let processor = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let config = ProcessorConfig {
+            resume_param: None,
+            ping_interval: Some(Duration::from_millis(20)),
+            pong_timeout: Duration::from_millis(50),
+            staleness_check: None,
+        };
+        let health = Arc::new(RwLock::new(HealthStatus::Starting));
+        let connection = processor_connection(
+            url,
+            &config,
+            Arc::new(Router::new(10, SymbolRegistry::default())),
+            health.clone(),
+            Arc::new(ProcessorStats::default()),
+            Arc::new(RwLock::new(None)),
+        );
+        let result = tokio::time::timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("The connection was kept despite the missed pong.");
+        assert!(matches!(result, Err(ConnectionError::ConnectionLost)));
+        assert!(matches!(*health.read().await, HealthStatus::Dead));
+        processor.abort();
+    }
+
+    #[test]
+    fn test_resume_url() {
+        assert_eq!(
+            resume_url("ws://processor:3008/ws", "from_version", 42),
+            "ws://processor:3008/ws?from_version=42"
+        );
+        assert_eq!(
+            resume_url("ws://processor:3008/ws?token=a", "from_version", 42),
+            "ws://processor:3008/ws?token=a&from_version=42"
+        );
+    }
 }
@@ -0,0 +1,416 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use processor::emojicoin_dot_fun::{EmojicoinDbEvent, Period};
+use strum::{Display, EnumString};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    types::{SequencedEvent, ServerMessage},
+    util::get_market_id,
+};
+
+/// What to do when a client does not read events as fast as they are queued for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued event.
+    DropOldest,
+    /// Drop a queued state event superseded by the new one, or the oldest queued event if there is
+    /// none, counting it as dropped either way.
+    Conflate,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Identifies state events of which only the most recent one matters to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConflationKey {
+    GlobalState,
+    MarketLatestState(u64),
+    PeriodicState(u64, Period),
+    Candlestick(u64, Period),
+    ArenaCandlestick(Period),
+}
+
+impl ConflationKey {
+    pub fn of(event: &EmojicoinDbEvent) -> Option<Self> {
+        match event {
+            EmojicoinDbEvent::GlobalState(_) => Some(Self::GlobalState),
+            EmojicoinDbEvent::MarketLatestState(_) => {
+                get_market_id(event).ok().map(Self::MarketLatestState)
+            }
+            EmojicoinDbEvent::PeriodicState(ps) => get_market_id(event)
+                .ok()
+                .map(|market_id| Self::PeriodicState(market_id, ps.period)),
+            EmojicoinDbEvent::Candlestick(candle) => get_market_id(event)
+                .ok()
+                .map(|market_id| Self::Candlestick(market_id, candle.period)),
+            EmojicoinDbEvent::ArenaCandlestick(candle) => {
+                Some(Self::ArenaCandlestick(candle.period))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Next thing to send to a client, as returned by [`ClientQueue::pop`].
+#[derive(Debug)]
+#[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+pub enum QueueItem {
+    Event(Arc<SequencedEvent>),
+    /// A reply to a client message.
+    Reply(ServerMessage),
+    /// Events were dropped since the last item.
+    Lagged(u64),
+    /// The queue overflowed under [`SlowConsumerPolicy::Disconnect`].
+    Overflowed,
+    Closed,
+}
+
+/// Bounded queue of events waiting to be sent to a single client.
+///
+/// Events are pushed without waiting, and the [`SlowConsumerPolicy`] is applied when the queue is
+/// full, so a slow client never holds up the delivery to other clients.
+///
+/// Replies to client messages are never dropped, and are sent in order with the events, after those
+/// queued before them. A client that lets as many replies as the queue capacity pile up is
+/// considered to overflow the queue.
+///
+/// If the client set a conflation window, at most one state event per [`ConflationKey`] is queued
+/// per window. The newest of the others is held back until the window ends, so the client always
+/// ends up with the latest state, possibly after more recent events of other kinds. Held back
+/// events are never dropped, and the newest queued event of each key is only dropped once no other
+/// event can be.
+pub struct ClientQueue {
+    inner: Mutex<ClientQueueInner>,
+    notify: Notify,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+#[derive(Default)]
+struct ClientQueueInner {
+    /// Queued [`QueueItem::Event`]s and [`QueueItem::Reply`]s by slot, in order.
+    entries: BTreeMap<u64, QueueItem>,
+    /// Slot of the next queued entry.
+    next_slot: u64,
+    /// Slots of the queued events.
+    events: BTreeSet<u64>,
+    /// Slots of the queued events without a [`ConflationKey`].
+    unkeyed: BTreeSet<u64>,
+    /// Slots of the queued events of each [`ConflationKey`].
+    keyed: HashMap<ConflationKey, BTreeSet<u64>>,
+    /// Slot of the oldest queued event of each key superseded by a newer queued or held back event,
+    /// kept up to date so that the queue is never scanned to choose the event to drop.
+    superseded: BTreeSet<u64>,
+    /// Number of replies in `entries`.
+    replies: usize,
+    dropped: u64,
+    overflowed: bool,
+    closed: bool,
+    conflation_window: Option<Duration>,
+    /// Newest state event of each key waiting for the end of its conflation window.
+    held: HashMap<ConflationKey, Arc<SequencedEvent>>,
+    /// When a state event of each key was last queued.
+    last_queued: HashMap<ConflationKey, Instant>,
+}
+
+impl ClientQueueInner {
+    /// Slot of the queued event to drop when the queue is full, if no superseded one is dropped
+    /// under [`SlowConsumerPolicy::Conflate`].
+    ///
+    /// While conflating, the newest state event of a key may be the final value the client is
+    /// guaranteed to receive, so the oldest event without a [`ConflationKey`] is dropped first,
+    /// then the oldest state event superseded by a newer one, and only then the oldest event.
+    fn eviction_slot(&self) -> Option<u64> {
+        if self.conflation_window.is_some() {
+            self.unkeyed
+                .first()
+                .or_else(|| self.superseded.first())
+                .or_else(|| self.events.first())
+                .copied()
+        } else {
+            self.events.first().copied()
+        }
+    }
+
+    /// Slot of the oldest queued event of the key.
+    fn oldest_of(&self, key: &ConflationKey) -> Option<u64> {
+        self.keyed.get(key).and_then(|slots| slots.first()).copied()
+    }
+
+    /// Runs a change to the queued or held back events of a key, updating `superseded`.
+    fn update_key<T>(&mut self, key: ConflationKey, f: impl FnOnce(&mut Self) -> T) -> T {
+        if let Some(slot) = self.oldest_of(&key) {
+            self.superseded.remove(&slot);
+        }
+        let result = f(self);
+        // Sets of slots are removed once empty.
+        if let Some(slots) = self.keyed.get(&key) {
+            if slots.len() > 1 || self.held.contains_key(&key) {
+                self.superseded.extend(slots.first());
+            }
+        }
+        result
+    }
+
+    fn push_event(&mut self, item: Arc<SequencedEvent>) {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        match ConflationKey::of(&item.event) {
+            Some(key) => self.update_key(key, |inner| {
+                inner.keyed.entry(key).or_default().insert(slot);
+            }),
+            None => {
+                self.unkeyed.insert(slot);
+            }
+        }
+        self.events.insert(slot);
+        self.entries.insert(slot, QueueItem::Event(item));
+    }
+
+    fn push_reply(&mut self, reply: ServerMessage) {
+        self.entries.insert(self.next_slot, QueueItem::Reply(reply));
+        self.next_slot += 1;
+        self.replies += 1;
+    }
+
+    /// Removes the queued event at the given slot.
+    fn remove_event(&mut self, slot: u64) -> Option<QueueItem> {
+        let entry = self.entries.remove(&slot)?;
+        let QueueItem::Event(item) = &entry else {
+            unreachable!("Slot {slot} is not an event.");
+        };
+        match ConflationKey::of(&item.event) {
+            Some(key) => self.update_key(key, |inner| {
+                if let Some(slots) = inner.keyed.get_mut(&key) {
+                    slots.remove(&slot);
+                    if slots.is_empty() {
+                        inner.keyed.remove(&key);
+                    }
+                }
+            }),
+            None => {
+                self.unkeyed.remove(&slot);
+            }
+        }
+        self.events.remove(&slot);
+        Some(entry)
+    }
+
+    /// Drops the queued event at the given slot.
+    fn drop_event(&mut self, slot: u64) {
+        if self.remove_event(slot).is_some() {
+            self.dropped += 1;
+        }
+    }
+
+    /// Holds back the newest state event of a key until the end of its conflation window.
+    fn hold(&mut self, key: ConflationKey, item: Arc<SequencedEvent>) {
+        self.update_key(key, |inner| inner.held.insert(key, item));
+    }
+
+    /// Takes the held back event of a key.
+    fn take_held(&mut self, key: ConflationKey) -> Option<Arc<SequencedEvent>> {
+        self.update_key(key, |inner| inner.held.remove(&key))
+    }
+
+    /// Drops every queued and held back item.
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.events.clear();
+        self.unkeyed.clear();
+        self.keyed.clear();
+        self.superseded.clear();
+        self.replies = 0;
+        self.held.clear();
+    }
+
+    /// End of the conflation window of the key, if one is running.
+    fn window_end(&self, key: &ConflationKey) -> Option<Instant> {
+        let window = self.conflation_window?;
+        self.last_queued
+            .get(key)
+            .map(|queued| *queued + window)
+            .filter(|end| *end > Instant::now())
+    }
+}
+
+impl ClientQueue {
+    #[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            inner: Mutex::new(ClientQueueInner::default()),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+
+    /// Sets the conflation window of state events, or disables conflation.
+    ///
+    /// Events held back by a previous window are queued right away.
+    pub fn set_conflation_window(&self, window: Option<Duration>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.conflation_window == window {
+            return;
+        }
+        inner.conflation_window = window;
+        inner.last_queued.clear();
+        let keys: Vec<_> = inner.held.keys().copied().collect();
+        let mut held: Vec<_> = keys
+            .into_iter()
+            .filter_map(|key| inner.take_held(key))
+            .collect();
+        held.sort_by_key(|item| item.sequence);
+        for item in held {
+            self.enqueue(&mut inner, item);
+        }
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Queues an event, applying the slow consumer policy if the queue is full.
+    pub fn push(&self, item: Arc<SequencedEvent>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed || inner.overflowed {
+            return;
+        }
+        if inner.conflation_window.is_some() {
+            if let Some(key) = ConflationKey::of(&item.event) {
+                if inner.held.contains_key(&key) || inner.window_end(&key).is_some() {
+                    inner.hold(key, item);
+                    drop(inner);
+                    // Lets a waiting `pop` know when the window ends.
+                    self.notify.notify_one();
+                    return;
+                }
+                inner.last_queued.insert(key, Instant::now());
+            }
+        }
+        self.enqueue(&mut inner, item);
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Queues held back events whose conflation window ended, and returns when the next one ends.
+    fn release_held(&self, inner: &mut ClientQueueInner) -> Option<Instant> {
+        let mut next_end = None;
+        let mut released = vec![];
+        for key in inner.held.keys() {
+            match inner.window_end(key) {
+                Some(end) => next_end = Some(next_end.map_or(end, |next: Instant| next.min(end))),
+                None => released.push(*key),
+            }
+        }
+        let mut released: Vec<_> = released
+            .into_iter()
+            .filter_map(|key| inner.take_held(key).map(|item| (key, item)))
+            .collect();
+        released.sort_by_key(|(_, item)| item.sequence);
+        let now = Instant::now();
+        for (key, item) in released {
+            inner.last_queued.insert(key, now);
+            self.enqueue(inner, item);
+        }
+        next_end
+    }
+
+    /// Adds an event to the queue, applying the slow consumer policy if it is full.
+    fn enqueue(&self, inner: &mut ClientQueueInner, item: Arc<SequencedEvent>) {
+        if inner.overflowed {
+            return;
+        }
+        if inner.events.len() >= self.capacity {
+            let slot = match self.policy {
+                SlowConsumerPolicy::DropOldest => inner.eviction_slot(),
+                SlowConsumerPolicy::Conflate => ConflationKey::of(&item.event)
+                    .and_then(|key| inner.oldest_of(&key))
+                    .or_else(|| inner.eviction_slot()),
+                SlowConsumerPolicy::Disconnect => {
+                    inner.overflowed = true;
+                    inner.clear();
+                    return;
+                }
+            };
+            if let Some(slot) = slot {
+                inner.drop_event(slot);
+            }
+        }
+        inner.push_event(item);
+    }
+
+    /// Number of events waiting to be sent, including those held back by the conflation window.
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.events.len() + inner.held.len()
+    }
+
+    /// Queues a reply to a client message.
+    pub fn push_reply(&self, reply: ServerMessage) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed || inner.overflowed {
+            return;
+        }
+        if inner.replies >= self.capacity {
+            inner.overflowed = true;
+            inner.clear();
+        } else {
+            inner.push_reply(reply);
+        }
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Closes the queue, after which [`ClientQueue::pop`] returns [`QueueItem::Closed`].
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next item to send.
+    #[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+    pub async fn pop(&self) -> QueueItem {
+        loop {
+            let next_window_end = {
+                let mut inner = self.inner.lock().unwrap();
+                let next_window_end = self.release_held(&mut inner);
+                if inner.closed {
+                    return QueueItem::Closed;
+                }
+                if inner.overflowed {
+                    return QueueItem::Overflowed;
+                }
+                // Sent before the next event.
+                let next = inner
+                    .entries
+                    .first_key_value()
+                    .map(|(slot, entry)| (*slot, matches!(entry, QueueItem::Reply(_))));
+                let next_is_reply = matches!(next, Some((_, true)));
+                if inner.dropped > 0 && !next_is_reply {
+                    return QueueItem::Lagged(std::mem::take(&mut inner.dropped));
+                }
+                match next {
+                    Some((slot, true)) => {
+                        inner.replies -= 1;
+                        return inner.entries.remove(&slot).unwrap();
+                    }
+                    Some((slot, false)) => return inner.remove_event(slot).unwrap(),
+                    None => {}
+                }
+                next_window_end
+            };
+            match next_window_end {
+                Some(end) => {
+                    let _ = tokio::time::timeout_at(end, self.notify.notified()).await;
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use processor::emojicoin_dot_fun::EmojicoinDbEvent;
+use serde::Deserialize;
+
+use crate::{types::MarketRef, util::get_market_id};
+
+/// A market as listed in the file the registry is seeded from, e.g.
+/// `{ "market_id": 1, "symbol_emojis": ["🧧"] }`.
+#[derive(Deserialize, Debug)]
+struct RegisteredMarket {
+    market_id: u64,
+    symbol_emojis: Vec<String>,
+}
+
+/// Market IDs by symbol, so that clients can subscribe to markets by their emojis.
+///
+/// It is seeded with the markets registered before the broker started, and learns new ones from
+/// `MarketRegistration` events.
+#[derive(Default)]
+pub struct SymbolRegistry {
+    market_ids: HashMap<Vec<u8>, u64>,
+}
+
+impl SymbolRegistry {
+    /// Reads a JSON array of markets with their `market_id` and `symbol_emojis`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let markets: Vec<RegisteredMarket> =
+            serde_json::from_str(&json).map_err(|e| format!("{path}: {e}"))?;
+        let mut registry = Self::default();
+        for market in markets {
+            registry.insert(market.symbol_emojis.concat().into_bytes(), market.market_id);
+        }
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, symbol_bytes: Vec<u8>, market_id: u64) {
+        self.market_ids.insert(symbol_bytes, market_id);
+    }
+
+    /// Registers the market if the event is a market registration.
+    pub fn update(&mut self, event: &EmojicoinDbEvent) {
+        if let EmojicoinDbEvent::MarketRegistration(registration) = event {
+            if let Ok(market_id) = get_market_id(event) {
+                self.insert(registration.symbol_bytes.clone(), market_id);
+            }
+        }
+    }
+
+    /// Returns the ID of the market, if it is known.
+    pub fn resolve(&self, market: &MarketRef) -> Option<u64> {
+        match market {
+            MarketRef::Id(market_id) => Some(*market_id),
+            MarketRef::Symbol(symbol) => self.market_ids.get(&parse_symbol(symbol)).copied(),
+            MarketRef::SymbolBytes(bytes) => self.market_ids.get(bytes).copied(),
+        }
+    }
+
+    /// Replaces the symbols of known markets with their ID, and returns the unknown ones.
+    ///
+    /// Unknown symbols are left as is, so that a list of unknown markets is not mistaken for an
+    /// empty one.
+    pub fn resolve_all(&self, markets: &mut [MarketRef]) -> Vec<String> {
+        let mut unknown = vec![];
+        for market in markets {
+            match self.resolve(market) {
+                Some(market_id) => *market = MarketRef::Id(market_id),
+                None => unknown.push(market.to_string()),
+            }
+        }
+        unknown
+    }
+}
+
+/// Returns the symbol bytes of emojis, or of their hex encoding if it starts with `0x`.
+fn parse_symbol(symbol: &str) -> Vec<u8> {
+    let decoded = symbol.strip_prefix("0x").and_then(|hex| {
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    });
+    decoded.unwrap_or_else(|| symbol.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut registry = SymbolRegistry::default();
+        registry.insert("🧧".as_bytes().to_vec(), 7);
+        registry.insert("🔥🔥".as_bytes().to_vec(), 8);
+
+        let resolve = |market: MarketRef| registry.resolve(&market);
+        assert_eq!(resolve(MarketRef::Id(3)), Some(3));
+        assert_eq!(resolve(MarketRef::Symbol("🧧".into())), Some(7));
+        assert_eq!(resolve(MarketRef::Symbol("0xf09fa7a7".into())), Some(7));
+        assert_eq!(
+            resolve(MarketRef::SymbolBytes(vec![240, 159, 167, 167])),
+            Some(7)
+        );
+        assert_eq!(resolve(MarketRef::Symbol("🔥🔥".into())), Some(8));
+        assert_eq!(resolve(MarketRef::Symbol("🔥".into())), None);
+        assert_eq!(resolve(MarketRef::Symbol("0xf09f".into())), None);
+
+        let mut markets = vec![
+            MarketRef::Symbol("🔥🔥".into()),
+            MarketRef::Symbol("🐸".into()),
+            MarketRef::SymbolBytes(vec![0xab]),
+        ];
+        assert_eq!(registry.resolve_all(&mut markets), vec!["🐸", "0xab"]);
+        assert_eq!(markets[0], MarketRef::Id(8));
+        assert_eq!(markets[1], MarketRef::Symbol("🐸".into()));
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use processor::emojicoin_dot_fun::EmojicoinDbEvent;
+
+use crate::{
+    snapshot::LatestState,
+    types::{SequencedEvent, Since},
+};
+
+/// Keeps the most recent events in memory so that clients can catch up on what they missed.
+///
+/// Each pushed event is assigned a monotonically increasing broker sequence number, the first one
+/// being 1, along with the epoch of the buffer, which tells sequence numbers of different broker
+/// processes apart.
+pub struct ReplayBuffer {
+    events: VecDeque<Arc<SequencedEvent>>,
+    epoch: u64,
+    last_sequence: u64,
+    latest_state: LatestState,
+    capacity: usize,
+}
+
+/// Events returned by [`ReplayBuffer::since`].
+pub struct Replay {
+    pub events: Vec<Arc<SequencedEvent>>,
+    /// True if some of the requested events were already evicted from the buffer.
+    pub truncated: bool,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            last_sequence: 0,
+            latest_state: LatestState::default(),
+            capacity,
+        }
+    }
+
+    /// Assigns the next sequence number to the event and stores it.
+    ///
+    /// State events are also kept in the latest state until they are superseded.
+    pub fn push(&mut self, event: EmojicoinDbEvent) -> Arc<SequencedEvent> {
+        self.last_sequence += 1;
+        let event = Arc::new(SequencedEvent::new(event, self.epoch, self.last_sequence));
+        self.latest_state.update(&event);
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        if self.capacity > 0 {
+            self.events.push_back(event.clone());
+        }
+        event
+    }
+
+    /// Returns the buffered events after the given position.
+    ///
+    /// A position of another epoch can't be resumed from, as the events between it and the
+    /// start of this epoch are unknown, so all buffered events are returned as a truncated replay.
+    pub fn since(&self, since: Since) -> Replay {
+        if since.epoch.is_some_and(|epoch| epoch != self.epoch) {
+            return Replay {
+                events: self.events.iter().cloned().collect(),
+                truncated: true,
+            };
+        }
+        let after = since.sequence;
+        let oldest = self
+            .events
+            .front()
+            .map(|e| e.sequence)
+            .unwrap_or(self.last_sequence + 1);
+        Replay {
+            events: self
+                .events
+                .iter()
+                .skip_while(|e| e.sequence <= after)
+                .cloned()
+                .collect(),
+            truncated: after + 1 < oldest && after < self.last_sequence,
+        }
+    }
+
+    /// Returns the latest state events.
+    pub fn latest_state(&self) -> Vec<Arc<SequencedEvent>> {
+        self.latest_state.events()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_since_other_epoch() {
+        let buffer = ReplayBuffer::new(10);
+        let since = |epoch| Since { epoch, sequence: 0 };
+        assert!(!buffer.since(since(None)).truncated);
+        assert!(!buffer.since(since(Some(buffer.epoch))).truncated);
+        assert!(buffer.since(since(Some(buffer.epoch - 1))).truncated);
+    }
+}
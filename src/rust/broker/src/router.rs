@@ -0,0 +1,576 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, warn};
+use processor::emojicoin_dot_fun::{EmojicoinDbEvent, EmojicoinDbEventType, Period};
+
+use crate::{
+    metrics::metrics,
+    queue::ClientQueue,
+    registry::SymbolRegistry,
+    replay::ReplayBuffer,
+    types::{ClientSubscription, MarketRef, RoutingKey, ServerMessage, Since},
+    util::{get_addresses, get_routing_key, is_address_match, is_match, is_value_match},
+};
+
+pub type ConnectionId = u64;
+
+/// Subscriptions of all connected clients, indexed by what they cover so that finding the clients
+/// that should receive an event doesn't require checking every subscription.
+#[derive(Default)]
+pub struct SubscriptionIndex {
+    subscriptions: HashMap<ConnectionId, ClientSubscription>,
+    /// Connections covering all markets.
+    all_markets: HashSet<ConnectionId>,
+    markets: HashMap<u64, HashSet<ConnectionId>>,
+    /// Connections covering all event types.
+    all_event_types: HashSet<ConnectionId>,
+    event_types: HashMap<EmojicoinDbEventType, HashSet<ConnectionId>>,
+    market_periods: HashMap<(u64, Period), HashSet<ConnectionId>>,
+    arena: HashSet<ConnectionId>,
+    arena_periods: HashMap<Period, HashSet<ConnectionId>>,
+}
+
+/// Dimensions of the subscriptions counted in the `broker_subscriptions` metric, `all` counting
+/// every subscription.
+pub const DIMENSIONS: [&str; 11] = [
+    "all",
+    "all_markets",
+    "markets",
+    "all_event_types",
+    "event_types",
+    "candlesticks",
+    "arena",
+    "arena_candlesticks",
+    "addresses",
+    "filters",
+    "conflation",
+];
+
+/// Returns the dimensions a subscription uses, e.g. covering specific markets.
+fn dimensions(sub: &ClientSubscription) -> Vec<&'static str> {
+    let uses = [
+        true,
+        sub.all_markets,
+        !sub.markets.is_empty(),
+        sub.all_event_types,
+        !sub.event_types.is_empty(),
+        !sub.market_candlestick_periods.is_empty(),
+        sub.arena,
+        !sub.arena_candlestick_periods.is_empty(),
+        !sub.addresses.is_empty(),
+        !sub.filters.is_empty(),
+        sub.conflation_ms.is_some(),
+    ];
+    DIMENSIONS
+        .into_iter()
+        .zip(uses)
+        .filter_map(|(dimension, used)| used.then_some(dimension))
+        .collect()
+}
+
+fn add<K: Eq + Hash>(map: &mut HashMap<K, HashSet<ConnectionId>>, key: K, id: ConnectionId) {
+    map.entry(key).or_default().insert(id);
+}
+
+fn remove<K: Eq + Hash>(map: &mut HashMap<K, HashSet<ConnectionId>>, key: &K, id: ConnectionId) {
+    if let Some(ids) = map.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl SubscriptionIndex {
+    pub fn get(&self, id: ConnectionId) -> Option<&ClientSubscription> {
+        self.subscriptions.get(&id)
+    }
+
+    /// Sets the subscription of a connection, replacing the previous one if any.
+    ///
+    /// The subscription counts of the metrics are updated along with the index, so that exporting
+    /// them doesn't require going through every subscription.
+    pub fn insert(&mut self, id: ConnectionId, subscription: ClientSubscription) {
+        self.remove(id);
+        metrics().subscribed(&dimensions(&subscription), 1);
+        if subscription.all_markets {
+            self.all_markets.insert(id);
+        }
+        for market_id in &subscription.markets {
+            add(&mut self.markets, *market_id, id);
+        }
+        if subscription.all_event_types {
+            self.all_event_types.insert(id);
+        }
+        for event_type in &subscription.event_types {
+            add(&mut self.event_types, *event_type, id);
+        }
+        for market_period in &subscription.market_candlestick_periods {
+            add(&mut self.market_periods, *market_period, id);
+        }
+        if subscription.arena {
+            self.arena.insert(id);
+        }
+        for period in &subscription.arena_candlestick_periods {
+            add(&mut self.arena_periods, *period, id);
+        }
+        self.subscriptions.insert(id, subscription);
+    }
+
+    /// Removes the subscription of a connection, returning it.
+    pub fn remove(&mut self, id: ConnectionId) -> Option<ClientSubscription> {
+        let subscription = self.subscriptions.remove(&id)?;
+        metrics().subscribed(&dimensions(&subscription), -1);
+        self.all_markets.remove(&id);
+        for market_id in &subscription.markets {
+            remove(&mut self.markets, market_id, id);
+        }
+        self.all_event_types.remove(&id);
+        for event_type in &subscription.event_types {
+            remove(&mut self.event_types, event_type, id);
+        }
+        for market_period in &subscription.market_candlestick_periods {
+            remove(&mut self.market_periods, market_period, id);
+        }
+        self.arena.remove(&id);
+        for period in &subscription.arena_candlestick_periods {
+            remove(&mut self.arena_periods, period, id);
+        }
+        Some(subscription)
+    }
+
+    /// Returns the connections whose subscription covers events with the given routing key.
+    ///
+    /// This returns the same connections as checking each subscription with
+    /// [`is_key_match`](crate::util::is_key_match).
+    pub fn matches(&self, key: &RoutingKey) -> Vec<ConnectionId> {
+        let empty = HashSet::new();
+        match key {
+            RoutingKey::Arena => self.arena.iter().copied().collect(),
+            RoutingKey::ArenaCandlestick(period) => self
+                .arena_periods
+                .get(period)
+                .unwrap_or(&empty)
+                .iter()
+                .copied()
+                .collect(),
+            RoutingKey::Candlestick(market_id, period) => self
+                .market_periods
+                .get(&(*market_id, *period))
+                .unwrap_or(&empty)
+                .iter()
+                .copied()
+                .collect(),
+            RoutingKey::Global(event_type) => {
+                let event_types = self.event_types.get(event_type).unwrap_or(&empty);
+                self.all_event_types
+                    .iter()
+                    .chain(
+                        event_types
+                            .iter()
+                            .filter(|id| !self.all_event_types.contains(id)),
+                    )
+                    .copied()
+                    .collect()
+            }
+            RoutingKey::Market(event_type, market_id) => {
+                // A connection matches if it covers both the market and the event type, so go
+                // through the smaller side and check the other one. A connection can be in both the
+                // `all_*` set and a specific set, so it is skipped in the latter.
+                let markets = self.markets.get(market_id).unwrap_or(&empty);
+                let event_types = self.event_types.get(event_type).unwrap_or(&empty);
+                let market_count = self.all_markets.len() + markets.len();
+                let event_type_count = self.all_event_types.len() + event_types.len();
+                if market_count <= event_type_count {
+                    self.all_markets
+                        .iter()
+                        .chain(markets.iter().filter(|id| !self.all_markets.contains(id)))
+                        .filter(|id| self.all_event_types.contains(id) || event_types.contains(id))
+                        .copied()
+                        .collect()
+                } else {
+                    self.all_event_types
+                        .iter()
+                        .chain(
+                            event_types
+                                .iter()
+                                .filter(|id| !self.all_event_types.contains(id)),
+                        )
+                        .filter(|id| self.all_markets.contains(id) || markets.contains(id))
+                        .copied()
+                        .collect()
+                }
+            }
+        }
+    }
+}
+
+/// Delivers published events to the clients whose subscription covers them, under the same lock as
+/// subscription changes so that replayed and snapshot events come before later live events.
+pub struct Router {
+    inner: Mutex<RouterInner>,
+}
+
+struct RouterInner {
+    replay_buffer: ReplayBuffer,
+    symbols: SymbolRegistry,
+    index: SubscriptionIndex,
+    queues: HashMap<ConnectionId, Arc<ClientQueue>>,
+    next_id: ConnectionId,
+}
+
+impl Router {
+    pub fn new(replay_buffer_size: usize, symbols: SymbolRegistry) -> Self {
+        Self {
+            inner: Mutex::new(RouterInner {
+                replay_buffer: ReplayBuffer::new(replay_buffer_size),
+                symbols,
+                index: SubscriptionIndex::default(),
+                queues: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Assigns the next sequence number to the event and queues it for all matching clients.
+    pub fn publish(&self, event: EmojicoinDbEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.symbols.update(&event);
+        let item = inner.replay_buffer.push(event);
+        let key = match get_routing_key(&item.event) {
+            Ok(key) => key,
+            Err(msg) => {
+                error!("{msg}");
+                return;
+            }
+        };
+        // Computed on first use, as most subscriptions are not filtered by address.
+        let mut addresses = None;
+        for id in inner.index.matches(&key) {
+            // Addresses and values are not indexed, as they only narrow down the subscriptions.
+            let Some(sub) = inner.index.get(id) else {
+                continue;
+            };
+            if !sub.addresses.is_empty() {
+                let addresses = addresses.get_or_insert_with(|| get_addresses(&item.event));
+                if !is_address_match(sub, addresses.as_deref()) {
+                    continue;
+                }
+            }
+            if !is_value_match(sub, &item.event) {
+                continue;
+            }
+            if let Some(queue) = inner.queues.get(&id) {
+                queue.push(item.clone());
+            }
+        }
+    }
+
+    /// Returns the number of connected clients, and of events waiting to be sent to them.
+    pub fn queue_stats(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        let queued = inner.queues.values().map(|queue| queue.len()).sum();
+        (inner.queues.len(), queued)
+    }
+
+    /// Registers a client, which will receive events in the given queue once it has a
+    /// subscription.
+    ///
+    /// The client is unregistered when the returned [`Connection`] is dropped.
+    #[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+    pub fn connect(self: &Arc<Self>, queue: Arc<ClientQueue>) -> Connection {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.queues.insert(id, queue);
+        Connection {
+            router: self.clone(),
+            id,
+        }
+    }
+}
+
+/// A client registered with the [`Router`].
+pub struct Connection {
+    router: Arc<Router>,
+    id: ConnectionId,
+}
+
+impl Connection {
+    /// Replaces the symbols of known markets with their ID, and returns the unknown ones.
+    pub fn resolve_markets(&self, markets: &mut [MarketRef]) -> Vec<String> {
+        let inner = self.router.inner.lock().unwrap();
+        inner.symbols.resolve_all(markets)
+    }
+
+    /// Replaces the subscription of the client.
+    ///
+    /// Buffered events after `since` that match the new subscription are queued first, after a
+    /// [`ServerMessage::Truncated`] notice if some of them are no longer buffered. They are
+    /// followed by the latest state events that it covers and the previous one didn't if
+    /// `snapshot` is set, except those that were just replayed.
+    pub fn set_subscription(
+        &self,
+        subscription: Option<ClientSubscription>,
+        since: Option<Since>,
+        snapshot: bool,
+    ) {
+        let mut inner = self.router.inner.lock().unwrap();
+        let previous = inner.index.remove(self.id);
+        let queue = inner.queues[&self.id].clone();
+        // Set first, so that replayed and snapshot events are conflated as well.
+        queue.set_conflation_window(
+            subscription
+                .as_ref()
+                .and_then(|sub| sub.conflation_ms)
+                .map(Duration::from_millis),
+        );
+        let Some(subscription) = subscription else {
+            return;
+        };
+        let mut replayed = HashSet::new();
+        if let Some(since) = since {
+            let replay = inner.replay_buffer.since(since);
+            if replay.truncated {
+                warn!("Some events after {since:?} are no longer buffered.");
+                queue.push_reply(ServerMessage::Truncated {
+                    since: since.sequence,
+                });
+            }
+            for item in replay.events {
+                if is_match(&subscription, &item.event) {
+                    replayed.insert(item.sequence);
+                    queue.push(item);
+                }
+            }
+        }
+        // Queued after replayed events so that the client ends up with the latest state.
+        if snapshot {
+            for item in inner.replay_buffer.latest_state() {
+                let was_covered = previous.as_ref().is_some_and(|p| is_match(p, &item.event));
+                if is_match(&subscription, &item.event)
+                    && !was_covered
+                    && !replayed.contains(&item.sequence)
+                {
+                    queue.push(item);
+                }
+            }
+        }
+        inner.index.insert(self.id, subscription);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut inner = self.router.inner.lock().unwrap();
+        inner.index.remove(self.id);
+        inner.queues.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{types::ValueFilter, util::is_key_match};
+
+    fn subscription(
+        markets: &[u64],
+        event_types: &[EmojicoinDbEventType],
+        market_periods: &[(u64, Period)],
+        arena: bool,
+        arena_periods: &[Period],
+    ) -> ClientSubscription {
+        // Empty lists mean all, like in legacy subscription messages.
+        ClientSubscription {
+            all_markets: markets.is_empty(),
+            markets: markets.iter().copied().collect(),
+            all_event_types: event_types.is_empty(),
+            event_types: event_types.iter().copied().collect(),
+            addresses: HashSet::new(),
+            filters: ValueFilter::default(),
+            conflation_ms: None,
+            market_candlestick_periods: market_periods.iter().copied().collect(),
+            arena,
+            arena_candlestick_periods: arena_periods.iter().copied().collect(),
+        }
+    }
+
+    fn sorted(mut ids: Vec<ConnectionId>) -> Vec<ConnectionId> {
+        ids.sort();
+        ids
+    }
+
+    fn linear_scan(index: &SubscriptionIndex, key: &RoutingKey) -> Vec<ConnectionId> {
+        index
+            .subscriptions
+            .iter()
+            .filter(|(_, sub)| is_key_match(sub, key))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    #[test]
+    fn test_index_matches() {
+        let mut index = SubscriptionIndex::default();
+        index.insert(0, subscription(&[], &[], &[], false, &[]));
+        index.insert(1, subscription(&[1], &[], &[], false, &[]));
+        index.insert(
+            2,
+            subscription(&[], &[EmojicoinDbEventType::Swap], &[], true, &[]),
+        );
+        index.insert(
+            3,
+            subscription(
+                &[1, 2],
+                &[EmojicoinDbEventType::Chat],
+                &[(1, Period::OneMinute)],
+                false,
+                &[Period::OneHour],
+            ),
+        );
+
+        let swap_1 = RoutingKey::Market(EmojicoinDbEventType::Swap, 1);
+        let chat_2 = RoutingKey::Market(EmojicoinDbEventType::Chat, 2);
+        let chat_3 = RoutingKey::Market(EmojicoinDbEventType::Chat, 3);
+        let global_state = RoutingKey::Global(EmojicoinDbEventType::GlobalState);
+        assert_eq!(sorted(index.matches(&swap_1)), vec![0, 1, 2]);
+        assert_eq!(sorted(index.matches(&chat_2)), vec![0, 3]);
+        assert_eq!(sorted(index.matches(&chat_3)), vec![0]);
+        assert_eq!(sorted(index.matches(&global_state)), vec![0, 1]);
+        assert_eq!(sorted(index.matches(&RoutingKey::Arena)), vec![2]);
+        assert_eq!(
+            index.matches(&RoutingKey::Candlestick(1, Period::OneMinute)),
+            vec![3]
+        );
+        assert!(index
+            .matches(&RoutingKey::Candlestick(2, Period::OneMinute))
+            .is_empty());
+        assert_eq!(
+            index.matches(&RoutingKey::ArenaCandlestick(Period::OneHour)),
+            vec![3]
+        );
+
+        for key in [swap_1, chat_2, chat_3, global_state, RoutingKey::Arena] {
+            assert_eq!(
+                sorted(index.matches(&key)),
+                sorted(linear_scan(&index, &key))
+            );
+        }
+    }
+
+    #[test]
+    fn test_index_matches_once() {
+        // E.g. a subscription to all markets followed by a subscription to market 1.
+        let mut sub = subscription(&[1], &[EmojicoinDbEventType::Swap], &[], false, &[]);
+        sub.all_markets = true;
+        sub.all_event_types = true;
+        let mut index = SubscriptionIndex::default();
+        index.insert(0, sub);
+
+        let swap_1 = RoutingKey::Market(EmojicoinDbEventType::Swap, 1);
+        let global_swap = RoutingKey::Global(EmojicoinDbEventType::Swap);
+        assert_eq!(index.matches(&swap_1), vec![0]);
+        assert_eq!(index.matches(&global_swap), vec![0]);
+
+        // With more market than event type entries, the event types side is walked instead.
+        index.insert(
+            1,
+            subscription(&[], &[EmojicoinDbEventType::Chat], &[], false, &[]),
+        );
+        assert_eq!(index.matches(&swap_1), vec![0]);
+    }
+
+    #[test]
+    fn test_index_replace_and_remove() {
+        let mut index = SubscriptionIndex::default();
+        index.insert(0, subscription(&[1], &[], &[], true, &[]));
+        index.insert(0, subscription(&[2], &[], &[], false, &[]));
+
+        let swap_1 = RoutingKey::Market(EmojicoinDbEventType::Swap, 1);
+        let swap_2 = RoutingKey::Market(EmojicoinDbEventType::Swap, 2);
+        assert!(index.matches(&swap_1).is_empty());
+        assert!(index.matches(&RoutingKey::Arena).is_empty());
+        assert_eq!(index.matches(&swap_2), vec![0]);
+
+        assert!(index.remove(0).is_some());
+        assert!(index.remove(0).is_none());
+        assert!(index.matches(&swap_2).is_empty());
+        assert!(index.markets.is_empty());
+        assert!(index.all_event_types.is_empty());
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(
+            dimensions(&subscription(&[1, 2], &[], &[], false, &[])),
+            vec!["all", "markets", "all_event_types"]
+        );
+        assert_eq!(
+            dimensions(&subscription(&[], &[], &[], true, &[Period::OneMinute])),
+            vec![
+                "all",
+                "all_markets",
+                "all_event_types",
+                "arena",
+                "arena_candlesticks"
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored"]
+    fn bench_index_against_linear_scan() {
+        const CONNECTIONS: u64 = 10_000;
+        const MARKETS: u64 = 1_000;
+        const EVENTS: u64 = 10_000;
+        let event_types = [
+            EmojicoinDbEventType::Swap,
+            EmojicoinDbEventType::Chat,
+            EmojicoinDbEventType::Liquidity,
+            EmojicoinDbEventType::MarketLatestState,
+        ];
+
+        // Most clients follow a few markets, some follow everything.
+        let mut index = SubscriptionIndex::default();
+        for id in 0..CONNECTIONS {
+            let markets: Vec<u64> = if id % 100 == 0 {
+                vec![]
+            } else {
+                (0..3)
+                    .map(|i| (id * 7919 + i * 104_729) % MARKETS)
+                    .collect()
+            };
+            let types = if id % 3 == 0 {
+                vec![]
+            } else {
+                vec![event_types[(id % 4) as usize]]
+            };
+            index.insert(id, subscription(&markets, &types, &[], false, &[]));
+        }
+        let keys: Vec<RoutingKey> = (0..EVENTS)
+            .map(|i| RoutingKey::Market(event_types[(i % 4) as usize], (i * 31) % MARKETS))
+            .collect();
+
+        let start = Instant::now();
+        let indexed: usize = keys.iter().map(|key| index.matches(key).len()).sum();
+        let indexed_duration = start.elapsed();
+
+        let start = Instant::now();
+        let scanned: usize = keys.iter().map(|key| linear_scan(&index, key).len()).sum();
+        let scanned_duration = start.elapsed();
+
+        assert_eq!(indexed, scanned);
+        assert!(
+            indexed_duration * 10 < scanned_duration,
+            "{EVENTS} events to {CONNECTIONS} connections: index {indexed_duration:?}, linear scan \
+             {scanned_duration:?}."
+        );
+    }
+}
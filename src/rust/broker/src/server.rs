@@ -1,26 +1,157 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use axum::{extract::State, http::StatusCode, routing::get, Router};
-use log::{info, warn};
-use processor::emojicoin_dot_fun::EmojicoinDbEvent;
-use tokio::sync::{broadcast::Sender, RwLock};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use log::{debug, error, info, warn};
+use tokio::sync::{watch, RwLock};
 
-use crate::{util::shutdown_signal, HealthStatus};
+use crate::{
+    metrics::metrics,
+    processor_connection::ProcessorStats,
+    queue::SlowConsumerPolicy,
+    router::{self, Connection},
+    types::{
+        ClientError, ClientMessage, ClientSubscription, ErrorCode, HealthReport, ProcessorHealth,
+        ServerMessage, SubscriptionFilter,
+    },
+    util::{
+        apply_client_message, check_candlestick_markets, jitter, parse_client_message,
+        shutdown_signal,
+    },
+    HealthStatus,
+};
 
 #[cfg(feature = "sse")]
 mod sse;
 #[cfg(feature = "ws")]
 mod ws;
 
+/// Settings for the connections to clients.
+#[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Maximum number of events waiting to be sent to a client.
+    pub queue_size: usize,
+    /// What to do when a client's queue is full.
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Maximum number of markets, event types and candlestick periods in a client's subscription.
+    pub max_subscription_size: usize,
+    /// How often websocket clients are pinged, if they are.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub ping_interval: Option<Duration>,
+    /// How long a websocket client has to answer a ping, or to read a frame sent to it, before it
+    /// is disconnected.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub pong_timeout: Duration,
+    /// How long a websocket client can stay connected without subscribing, if it can't forever.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub idle_timeout: Option<Duration>,
+    /// Level of the compression websocket clients can ask for, from 1 to 9, if they can.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub compression_level: Option<u32>,
+    /// How long clients have to disconnect when the broker shuts down.
+    pub drain_period: Duration,
+    /// Longest delay after which clients are asked to reconnect when the broker shuts down.
+    pub reconnect_jitter: Duration,
+}
+
+/// A live SSE stream, whose subscription can be changed with a request to `/sse/:connection_id`.
+#[cfg_attr(not(feature = "sse"), allow(dead_code))]
+struct SseConnection {
+    connection: Connection,
+    subscription: Mutex<Option<ClientSubscription>>,
+}
+
 struct AppState {
-    #[allow(dead_code)]
-    tx: Sender<EmojicoinDbEvent>,
+    router: Arc<router::Router>,
     processor_connection_health: Arc<RwLock<HealthStatus>>,
+    processor_stats: Arc<ProcessorStats>,
+    client_config: ClientConfig,
+    /// Live SSE streams by connection ID.
+    #[cfg_attr(not(feature = "sse"), allow(dead_code))]
+    sse_connections: Mutex<HashMap<String, Arc<SseConnection>>>,
+    /// Becomes true when the broker shuts down, for clients to be told to go away.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl AppState {
+    /// Waits until the broker shuts down, and returns the delay after which the client should
+    /// reconnect.
+    #[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+    async fn going_away(&self) -> Duration {
+        let mut shutdown = self.shutdown.clone();
+        if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+            // The server is gone, which only happens once its connections are closed.
+            std::future::pending::<()>().await;
+        }
+        jitter(self.client_config.reconnect_jitter)
+    }
+}
+
+/// Applies a client message to the subscription of a connection.
+///
+/// Returns the acknowledgement, along with whether the client expects it, as legacy messages are
+/// only acknowledged if they have a request id. Market symbols are resolved to IDs, and the unknown
+/// ones are listed in the acknowledgement. Invalid messages leave the subscription unchanged.
+#[cfg_attr(not(any(feature = "ws", feature = "sse")), allow(dead_code))]
+fn apply_message(
+    msg: &str,
+    sub: &mut Option<ClientSubscription>,
+    connection: &Connection,
+    max_subscription_size: usize,
+) -> Result<(ServerMessage, bool), ClientError> {
+    let request = parse_client_message(msg).map_err(|e| {
+        warn!("Got invalid message from client: {}.", e.message);
+        e
+    })?;
+    let expected = request.id.is_some() || matches!(request.message, ClientMessage::V1(_));
+    let since = request.message.since();
+    let snapshot = request.message.snapshot();
+    let mut message = request.message;
+    let unknown_symbols = message
+        .markets_mut()
+        .map(|markets| connection.resolve_markets(markets))
+        .unwrap_or_default();
+    check_candlestick_markets(sub, &message)
+        .map_err(|e| ClientError::new(request.id.clone(), ErrorCode::InvalidMessage, e))?;
+    let mut new_sub = sub.clone();
+    apply_client_message(&mut new_sub, message);
+    let size = new_sub.as_ref().map_or(0, ClientSubscription::size);
+    if size > max_subscription_size {
+        return Err(ClientError::new(
+            request.id,
+            ErrorCode::LimitExceeded,
+            format!("Subscription size {size} exceeds the maximum of {max_subscription_size}."),
+        ));
+    }
+    *sub = new_sub;
+    debug!("Subscription updated ({sub:?}).");
+    connection.set_subscription(sub.clone(), since, snapshot);
+    let ack = ServerMessage::Ack {
+        id: request.id,
+        subscription: sub
+            .as_ref()
+            .map(|sub| Box::new(SubscriptionFilter::from(sub))),
+        unknown_symbols,
+    };
+    Ok((ack, expected))
 }
 
 #[cfg(all(feature = "sse", not(feature = "ws")))]
 fn prepare_app(app: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
-    app.route("/sse", get(sse::handler))
+    app.route("/sse", get(sse::handler)).route(
+        "/sse/:connection_id",
+        axum::routing::post(sse::update).patch(sse::update),
+    )
 }
 
 #[cfg(all(feature = "ws", not(feature = "sse")))]
@@ -32,6 +163,10 @@ fn prepare_app(app: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
 fn prepare_app(app: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
     app.route("/", get(ws::handler))
         .route("/sse", get(sse::handler))
+        .route(
+            "/sse/:connection_id",
+            axum::routing::post(sse::update).patch(sse::update),
+        )
 }
 
 #[cfg(all(not(feature = "ws"), not(feature = "sse")))]
@@ -41,27 +176,84 @@ fn prepare_app(app: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
 
 async fn live() {}
 
-async fn health(State(state): State<Arc<AppState>>) -> StatusCode {
-    match *state.processor_connection_health.read().await {
+/// Reports the state of the connection to the processor and of the clients.
+///
+/// Fails while the processor connection is sick, dead or stale, but not while it is starting, so
+/// that the broker isn't restarted before it had a chance to connect.
+async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let status = state.processor_connection_health.read().await.clone();
+    let code = match status {
         HealthStatus::Ok | HealthStatus::Starting => StatusCode::OK,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let stats = &state.processor_stats;
+    let progress = stats.transaction_version();
+    let (clients, queued_events) = state.router.queue_stats();
+    let report = HealthReport {
+        processor: ProcessorHealth {
+            status,
+            since_last_event_ms: stats.since_last_event().map(|d| d.as_millis() as u64),
+            transaction_version: progress.map(|(version, _)| version),
+            since_progress_ms: progress.map(|(_, d)| d.as_millis() as u64),
+            reconnects: stats.reconnects(),
+            retries_left: stats.retries_left(),
+        },
+        clients,
+        queued_events,
+    };
+    (code, Json(report))
+}
+
+/// Succeeds while the broker is connected to the processor and receiving its events, so that
+/// clients are only routed to brokers that are up to date.
+async fn ready(State(state): State<Arc<AppState>>) -> StatusCode {
+    match *state.processor_connection_health.read().await {
+        HealthStatus::Ok => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Exports the metrics of the broker in the Prometheus text format.
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let health = state.processor_connection_health.read().await.clone();
+    match metrics().render(&health) {
+        Ok(text) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            text,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Could not render metrics: {e}.");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
 }
 
 pub async fn server(
-    tx: Sender<EmojicoinDbEvent>,
+    router: Arc<router::Router>,
     port: u16,
     processor_connection_health: Arc<RwLock<HealthStatus>>,
+    processor_stats: Arc<ProcessorStats>,
+    client_config: ClientConfig,
 ) -> Result<(), std::io::Error> {
+    let drain_period = client_config.drain_period;
+    let (shutdown, shutdown_rx) = watch::channel(false);
     let app_state = AppState {
-        tx,
+        router,
         processor_connection_health: processor_connection_health.clone(),
+        processor_stats,
+        client_config,
+        sse_connections: Mutex::new(HashMap::new()),
+        shutdown: shutdown_rx,
     };
 
     let app = prepare_app(
         Router::new()
             .route("/live", get(live))
-            .route("/health", get(health)),
+            .route("/health", get(health))
+            .route("/ready", get(ready))
+            .route("/metrics", get(metrics_handler)),
     );
     let app = app.with_state(Arc::new(app_state));
 
@@ -82,9 +274,149 @@ pub async fn server(
         warn!("Starting web server with no endpoints.");
     }
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            let _ = shutdown_signal().await;
-        })
-        .await
+    // Stops accepting connections once shutting down, and waits for the open ones to close.
+    let mut stop = shutdown.subscribe();
+    let serve = async {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = stop.wait_for(|shutdown| *shutdown).await;
+            })
+            .await?;
+        // Websocket connections outlive their HTTP request, but keep the app state and its
+        // receiver until they are closed.
+        shutdown.closed().await;
+        Ok(())
+    };
+    tokio::pin!(serve);
+
+    tokio::select! {
+        result = &mut serve => return result,
+        result = shutdown_signal() => {
+            if let Err(e) = result {
+                error!("Could not listen to shutdown signals: {e}.");
+            }
+        }
+    }
+
+    info!("Shutting down, draining clients for up to {drain_period:?}.");
+    shutdown.send_replace(true);
+    match tokio::time::timeout(drain_period, serve).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Some clients were still connected after the drain period.");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use processor::emojicoin_dot_fun::EmojicoinDbEventType;
+
+    use crate::{
+        queue::ClientQueue,
+        registry::SymbolRegistry,
+        types::{ErrorCode, RoutingKey},
+        util::{is_key_match, new_connection_id},
+    };
+
+    use super::*;
+
+    fn connect() -> Connection {
+        let router = Arc::new(router::Router::new(10, SymbolRegistry::default()));
+        let queue = Arc::new(ClientQueue::new(10, SlowConsumerPolicy::DropOldest));
+        router.connect(queue)
+    }
+
+    #[test]
+    fn test_apply_message() {
+        let connection = connect();
+        let mut sub = None;
+
+        // Legacy messages are only acknowledged when they have a request id.
+        let (_, expected) =
+            apply_message(r#"{ "markets": [1] }"#, &mut sub, &connection, 10).unwrap();
+        assert!(!expected);
+        let (ack, expected) =
+            apply_message(r#"{ "id": 1, "markets": [2] }"#, &mut sub, &connection, 10).unwrap();
+        assert!(expected);
+        let ServerMessage::Ack {
+            id, subscription, ..
+        } = ack
+        else {
+            panic!("Expected an ack, got {ack:?}.");
+        };
+        assert_eq!(id, Some(1.into()));
+        assert_eq!(subscription.unwrap().markets, vec![2.into()]);
+
+        let (_, expected) = apply_message(
+            r#"{ "version": 1, "action": "subscribe", "markets": [3] }"#,
+            &mut sub,
+            &connection,
+            10,
+        )
+        .unwrap();
+        assert!(expected);
+        assert_eq!(sub.as_ref().unwrap().markets, [2, 3].into());
+
+        // Errors leave the subscription unchanged.
+        let e = apply_message(
+            r#"{ "id": "a", "version": 1, "action": "subscribe", "markets": [4, 5, 6, 7, 8, 9, 10, 11, 12] }"#,
+            &mut sub,
+            &connection,
+            10,
+        )
+        .unwrap_err();
+        assert_eq!(e.code, ErrorCode::LimitExceeded);
+        assert_eq!(e.id, Some("a".into()));
+        let e = apply_message("{", &mut sub, &connection, 10).unwrap_err();
+        assert_eq!(e.code, ErrorCode::BadJson);
+        assert_eq!(sub.as_ref().unwrap().markets, [2, 3].into());
+        assert!(!is_key_match(
+            sub.as_ref().unwrap(),
+            &RoutingKey::Market(EmojicoinDbEventType::Swap, 4)
+        ));
+    }
+
+    #[test]
+    fn test_apply_message_with_symbols() {
+        let mut symbols = SymbolRegistry::default();
+        symbols.insert("🧧".as_bytes().to_vec(), 7);
+        let router = Arc::new(router::Router::new(10, symbols));
+        let queue = Arc::new(ClientQueue::new(10, SlowConsumerPolicy::DropOldest));
+        let connection = router.connect(queue);
+        let mut sub = None;
+
+        let (ack, _) =
+            apply_message(r#"{ "markets": ["🧧", "🐸"] }"#, &mut sub, &connection, 10).unwrap();
+        let ServerMessage::Ack {
+            unknown_symbols, ..
+        } = ack
+        else {
+            panic!("Expected an ack, got {ack:?}.");
+        };
+        assert_eq!(unknown_symbols, vec!["🐸"]);
+        let sub = sub.unwrap();
+        assert_eq!(sub.markets, [7].into());
+        // Unknown markets don't make the list empty, which would mean all markets.
+        assert!(!sub.all_markets);
+    }
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        let max = Duration::from_millis(100);
+        let delays: HashSet<Duration> = (0..10).map(|_| jitter(max)).collect();
+        assert!(delays.iter().all(|delay| *delay <= max));
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn test_new_connection_id() {
+        let id = new_connection_id();
+        assert_eq!(id.len(), 32);
+        assert_ne!(id, new_connection_id());
+    }
 }
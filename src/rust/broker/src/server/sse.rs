@@ -1,68 +1,234 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
 
 use axum::{
-    extract::State,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive},
-        Sse,
+        IntoResponse, Response, Sse,
     },
+    Json,
 };
 use axum_extra::extract::Query;
-use futures_util::{Stream, StreamExt};
-use log::{error, info, trace, warn};
-use tokio::sync::broadcast::error::RecvError;
+use futures_util::StreamExt;
+use log::{info, warn};
 
 use crate::{
-    types::{ClientSubscription, SubscriptionMessage},
-    util::is_match,
+    metrics::metrics,
+    queue::{ClientQueue, QueueItem},
+    types::{ClientError, ClientSubscription, ErrorCode, ServerMessage, SseSubscriptionQuery},
+    util::{get_event_name, is_valid_address, new_connection_id},
 };
 
-use super::AppState;
+use super::{apply_message, AppState, SseConnection};
 
 /// Handles a request to `/sse`.
 ///
 /// Takes subscription data as query parameters.
 ///
+/// Each event is sent with its broker epoch and sequence number as `id`, e.g. `1760000000000:42`.
+/// Events are unnamed, so they are received as `message` events, unless the client connects with
+/// `named_events=true` to have them named after their type (e.g. `Swap`). When a client reconnects
+/// with a `Last-Event-ID` header, matching events it missed are replayed first. If the client is
+/// too slow and events are dropped, a `lagged` message is sent with the number of dropped events.
+///
+/// The first event is a `connection` message with the connection ID, which can be used to change
+/// the subscription with a request to `/sse/:connection_id`, see [`update`].
+///
 /// If a field is left empty, you will be subscribed to all.
 ///
 /// Example of connection paths:
 ///
 /// - `/sse?markets=1&event_types=Chat`: subscribe to chat events on market 1
+/// - `/sse?markets=🧧&event_types=Chat`: subscribe to chat events on the 🧧 market
 /// - `/sse?markets=1`: subscribe to all events on market 1
 /// - `/sse?event_types=State`: subscribe to all State events
 /// - `/sse`: subscribe to all events
 /// - `/sse?markets=1&markets=2&event_types=Chat&event_types=Swap`: subscribe to Chat and Swap events on markets 1 and 2
+/// - `/sse?markets=1&since=1760000000000:1234`: replay buffered events on market 1 after sequence number 1234 of epoch 1760000000000, then subscribe
+/// - `/sse?markets=1&snapshot=true`: send the latest state of market 1, then subscribe
+/// - `/sse?markets=12&candles=12:OneHour&candles=12:OneDay`: subscribe to all events on market 12, including its 1h and 1d candlesticks
+/// - `/sse?markets=12&candles=12`: subscribe to all events on market 12, including all its candlesticks
+/// - `/sse?arena=true&arena_candles=FifteenSeconds`: subscribe to arena events, including 15s arena candlesticks
+/// - `/sse?markets=1&named_events=true`: subscribe to all events on market 1, named after their type
+///
+/// Like websocket messages, a query with an invalid address or a subscription larger than allowed
+/// is rejected with an error and a `400 Bad Request` status.
 pub async fn handler(
-    Query(msg): Query<SubscriptionMessage>,
+    Query(mut msg): Query<SseSubscriptionQuery>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+) -> Response {
+    if let Some(address) = msg
+        .addresses
+        .iter()
+        .chain(&msg.integrator)
+        .find(|address| !is_valid_address(address))
+    {
+        let error = ClientError::new(
+            None,
+            ErrorCode::InvalidAddress,
+            format!("Invalid address {address:?}."),
+        );
+        return (StatusCode::BAD_REQUEST, Json(ServerMessage::Error(error))).into_response();
+    }
+
+    // The `Last-Event-ID` header is set by `EventSource` when reconnecting, so it is more recent
+    // than the `since` query parameter of the original URL.
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+    let since = last_event_id.or(msg.since);
+    let snapshot = msg.snapshot;
+    let named_events = msg.named_events;
+
+    let queue = Arc::new(ClientQueue::new(
+        state.client_config.queue_size,
+        state.client_config.slow_consumer_policy,
+    ));
+    let connection = state.router.connect(queue.clone());
+    let unknown_symbols = connection.resolve_markets(&mut msg.markets);
+    if !unknown_symbols.is_empty() {
+        warn!("Ignoring unknown market symbols {unknown_symbols:?}.");
+    }
     let subscription = ClientSubscription::from(msg);
+    let (size, max_size) = (
+        subscription.size(),
+        state.client_config.max_subscription_size,
+    );
+    if size > max_size {
+        let error = ClientError::new(
+            None,
+            ErrorCode::LimitExceeded,
+            format!("Subscription size {size} exceeds the maximum of {max_size}."),
+        );
+        return (StatusCode::BAD_REQUEST, Json(ServerMessage::Error(error))).into_response();
+    }
     info!("New SSE connection ({subscription:?}).");
+    connection.set_subscription(Some(subscription.clone()), since, snapshot);
+
+    let id = new_connection_id();
+    state.sse_connections.lock().unwrap().insert(
+        id.clone(),
+        Arc::new(SseConnection {
+            connection,
+            subscription: Mutex::new(Some(subscription)),
+        }),
+    );
+    let guard = SseConnectionGuard {
+        state: state.clone(),
+        id: id.clone(),
+    };
+
+    let transport = metrics().transport("sse");
+    let client = transport.connect();
 
-    let mut rx = state.tx.subscribe();
     let stream = async_stream::stream! {
+        // Unregisters the client when the stream is dropped.
+        let _guard = guard;
+        let _client = client;
+        let msg = ServerMessage::Connection { id };
+        yield name(Event::default(), named_events, "connection")
+            .data(serde_json::to_string(&msg).unwrap());
         loop {
-            let mut r = rx.recv().await;
-            while matches!(r, Err(RecvError::Lagged(_))) {
-                warn!("Messages dropped due to lag.");
-                r = rx.recv().await;
-            }
-            if let Ok(item) = r {
-                if is_match(&subscription, &item) {
-                    trace!("Event is a match.");
-                    yield item;
-                } else {
-                    trace!("Event is not a match");
+            let item = tokio::select! {
+                item = queue.pop() => item,
+                reconnect_after = state.going_away() => {
+                    let msg = ServerMessage::GoingAway {
+                        reconnect_after_ms: reconnect_after.as_millis() as u64,
+                    };
+                    // `EventSource` waits for the retry delay before reconnecting.
+                    yield name(Event::default(), named_events, "going_away")
+                        .retry(reconnect_after)
+                        .data(serde_json::to_string(&msg).unwrap());
+                    break;
+                }
+            };
+            match item {
+                QueueItem::Event(e) => {
+                    // Counted as sent once handed to the response body.
+                    transport.sent(e.json.len(), std::slice::from_ref(&e));
+                    yield name(Event::default(), named_events, get_event_name(&e.event))
+                        .id(format!("{}:{}", e.epoch, e.sequence))
+                        .data(&e.json);
+                }
+                QueueItem::Lagged(dropped) => {
+                    warn!("Dropped {dropped} messages due to lag.");
+                    transport.lagged(dropped);
+                    let msg = ServerMessage::Lagged { dropped };
+                    yield name(Event::default(), named_events, "lagged")
+                        .data(serde_json::to_string(&msg).unwrap());
+                }
+                QueueItem::Overflowed => {
+                    warn!("Client is too slow, closing connection.");
+                    break;
+                }
+                QueueItem::Closed => break,
+                // Notices from the broker, as replies to SSE clients are sent in the response to
+                // their request.
+                QueueItem::Reply(msg) => {
+                    let event_name: &'static str = (&msg).into();
+                    yield name(Event::default(), named_events, event_name)
+                        .data(serde_json::to_string(&msg).unwrap());
                 }
-            } else {
-                error!("Got unexpected error: {}.", r.unwrap_err());
             }
         }
     };
 
-    let stream = stream
-        .map(|e| Event::default().data(serde_json::to_string(&e).unwrap()))
-        .map(Ok);
+    let stream = stream.map(Ok::<_, Infallible>);
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Names the event, if the client asked for named events.
+fn name(event: Event, named_events: bool, name: &str) -> Event {
+    if named_events {
+        event.event(name)
+    } else {
+        event
+    }
+}
+
+/// Removes an SSE connection from the live connections when its stream is dropped.
+struct SseConnectionGuard {
+    state: Arc<AppState>,
+    id: String,
+}
+
+impl Drop for SseConnectionGuard {
+    fn drop(&mut self) {
+        self.state.sse_connections.lock().unwrap().remove(&self.id);
+        info!("SSE connection closed.");
+    }
+}
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+/// Handles a POST or PATCH request to `/sse/:connection_id`.
+///
+/// Applies a websocket client message from the body to the subscription of a live SSE stream,
+/// using the connection ID sent in its first event. Replies with the acknowledgement, or with an
+/// error and a `400 Bad Request` status if the message can't be applied.
+pub async fn update(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    body: String,
+) -> Response {
+    let Some(sse_connection) = state.sse_connections.lock().unwrap().get(&id).cloned() else {
+        return (StatusCode::NOT_FOUND, "Unknown SSE connection.").into_response();
+    };
+    let mut subscription = sse_connection.subscription.lock().unwrap();
+    match apply_message(
+        &body,
+        &mut subscription,
+        &sse_connection.connection,
+        state.client_config.max_subscription_size,
+    ) {
+        Ok((ack, _)) => Json(ack).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ServerMessage::Error(e))).into_response(),
+    }
 }
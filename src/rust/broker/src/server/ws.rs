@@ -1,89 +1,587 @@
-use std::sync::Arc;
+use std::{
+    future,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
         State, WebSocketUpgrade,
     },
-    response::Response,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::Query;
+use flate2::write::DeflateEncoder;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::time::{interval_at, sleep_until, timeout, timeout_at, Instant, Interval};
+
+use crate::{
+    metrics::{metrics, TransportMetrics},
+    queue::{ClientQueue, QueueItem},
+    types::{
+        ClientError, Compression, Encoding, ErrorCode, SequencedEvent, ServerMessage,
+        WsConnectionQuery,
+    },
 };
-use futures_util::{SinkExt, StreamExt};
-use log::{debug, error, info, warn};
-use tokio::sync::{broadcast::error::RecvError, RwLock};
 
-use crate::util::{is_match, update_subscription};
+use super::{apply_message, AppState};
+
+/// Close code sent to clients that are disconnected for not reading events fast enough.
+const SLOW_CONSUMER_CLOSE_CODE: u16 = 4000;
 
-use super::AppState;
+/// Close code sent to clients that are disconnected for not answering a ping in time, or not
+/// reading a frame in time.
+const PONG_TIMEOUT_CLOSE_CODE: u16 = 4001;
 
-pub async fn handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
-    ws.on_upgrade(|socket| handle_websocket(socket, state))
+/// Close code sent to clients that are disconnected for not subscribing in time.
+const IDLE_CLOSE_CODE: u16 = 4002;
+
+/// Close code sent to clients when the broker shuts down.
+const GOING_AWAY_CLOSE_CODE: u16 = 1001;
+
+/// Longest time events can be held back to be batched.
+const MAX_BATCH_MS: u64 = 1000;
+
+/// Number of events in a batch if the client doesn't set it, and most it can set.
+const DEFAULT_BATCH_SIZE: usize = 100;
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// Subprotocols that select an encoding, in order of preference.
+const SUBPROTOCOLS: [&str; 3] = ["cbor", "msgpack", "json"];
+
+/// How events are batched for a client that asked for it.
+#[derive(Debug, Clone, Copy)]
+struct Batching {
+    window: Duration,
+    max_events: usize,
 }
 
-async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
-    info!("New websocket connection.");
+impl Batching {
+    fn from_query(query: &WsConnectionQuery) -> Option<Self> {
+        let batch_ms = query.batch_ms?;
+        Some(Self {
+            window: Duration::from_millis(batch_ms.min(MAX_BATCH_MS)),
+            max_events: query
+                .batch_size
+                .unwrap_or(DEFAULT_BATCH_SIZE)
+                .clamp(1, MAX_BATCH_SIZE),
+        })
+    }
+}
 
-    let (ws_tx, mut ws_rx) = socket.split();
+/// How messages are turned into frames for a client.
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    encoding: Encoding,
+    batching: Option<Batching>,
+    compression: Option<flate2::Compression>,
+}
+
+/// Handles a websocket connection to `/`.
+///
+/// Messages are sent as JSON text frames, or as MessagePack or CBOR binary frames if the client
+/// selects the encoding with the `encoding` query parameter or the `msgpack` or `cbor` subprotocol.
+/// `permessage-deflate` is not negotiated, as `tungstenite` doesn't support it. Instead, clients
+/// can ask for each message to be compressed with `compression=deflate`, if `WS_COMPRESSION_LEVEL`
+/// is set, and are rejected otherwise.
+///
+/// With `batch_ms`, events are sent in array frames of up to `batch_size` events, in order,
+/// accumulated for up to `batch_ms` milliseconds. Other messages are still sent in their own frame,
+/// after the events before them.
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<WsConnectionQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let batching = Batching::from_query(&query);
+    let compression = match (query.compression, state.client_config.compression_level) {
+        (Some(Compression::Deflate), Some(level)) => Some(flate2::Compression::new(level)),
+        (Some(_), None) => {
+            let error = ClientError::new(
+                None,
+                ErrorCode::CompressionDisabled,
+                "Compression is disabled.",
+            );
+            return (StatusCode::BAD_REQUEST, Json(ServerMessage::Error(error))).into_response();
+        }
+        (None, _) => None,
+    };
+    ws.protocols(SUBPROTOCOLS).on_upgrade(move |socket| {
+        let encoding = query
+            .encoding
+            .or_else(|| socket.protocol()?.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        let framing = Framing {
+            encoding,
+            batching,
+            compression,
+        };
+        handle_websocket(socket, state, framing)
+    })
+}
 
-    let ws_tx = Arc::new(RwLock::new(ws_tx));
-    let ws_tx2 = ws_tx.clone();
+/// Makes a frame of a serialized message, which is text for JSON.
+fn frame(bytes: Vec<u8>, encoding: Encoding) -> Message {
+    match encoding {
+        Encoding::Json => Message::Text(String::from_utf8(bytes).unwrap()),
+        _ => Message::Binary(bytes),
+    }
+}
 
-    let sub = Arc::new(RwLock::new(None));
-    let sub2 = sub.clone();
+fn encode_event(item: &SequencedEvent, encoding: Encoding) -> Message {
+    match encoding {
+        Encoding::Json => Message::Text(item.json.clone()),
+        _ => Message::Binary(item.encoded(encoding).to_vec()),
+    }
+}
 
-    let mut rx = state.tx.subscribe();
+/// Serializes events as an array, reusing their serialized form.
+fn encode_batch(batch: &[Arc<SequencedEvent>], encoding: Encoding) -> Message {
+    let events: Vec<&[u8]> = batch.iter().map(|item| item.encoded(encoding)).collect();
+    frame(encoding.encode_array(&events), encoding)
+}
 
-    let r = async move {
-        while let Some(Ok(msg)) = ws_rx.next().await {
-            if let Ok(msg) = msg.to_text() {
-                let mut sub_lock = sub2.write().await;
-                if let Ok(_) = update_subscription(&mut sub_lock, msg) {
-                    debug!("Subscription updated ({sub_lock:?}).");
-                } else {
-                    warn!("Got invalid JSON format from client, closing connection.");
+fn encode_message(msg: &ServerMessage, encoding: Encoding) -> Message {
+    frame(encoding.encode(msg), encoding)
+}
+
+/// Compresses the payload of a frame with raw DEFLATE, into a binary frame.
+fn deflate(msg: Message, level: flate2::Compression) -> Message {
+    let payload = match msg {
+        Message::Text(text) => text.into_bytes(),
+        Message::Binary(bytes) => bytes,
+        msg => return msg,
+    };
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(payload.len() / 2), level);
+    // Writing to a vector can't fail.
+    encoder.write_all(&payload).unwrap();
+    Message::Binary(encoder.finish().unwrap())
+}
+
+/// Pops the events following the given one from the queue, until the batch is full or its window
+/// ends.
+///
+/// Returns the batch, along with the item that ended it if it isn't an event.
+async fn collect_batch(
+    first: Arc<SequencedEvent>,
+    queue: &ClientQueue,
+    batching: Batching,
+) -> (Vec<Arc<SequencedEvent>>, Option<QueueItem>) {
+    let mut batch = vec![first];
+    let deadline = Instant::now() + batching.window;
+    while batch.len() < batching.max_events {
+        // Popping is cancel safe, so no item is lost when the window ends.
+        match timeout_at(deadline, queue.pop()).await {
+            Ok(QueueItem::Event(item)) => batch.push(item),
+            Ok(item) => return (batch, Some(item)),
+            Err(_) => break,
+        }
+    }
+    (batch, None)
+}
+
+/// What the reader of a connection saw of the client, for the writer to detect dead connections.
+#[derive(Default)]
+struct ClientActivity {
+    /// Number of frames received from the client.
+    frames: AtomicU64,
+    /// Whether the client ever subscribed.
+    subscribed: AtomicBool,
+    /// Whether the client ever sent a message of the versioned protocol. Other clients only expect
+    /// events and acknowledgements they asked for, so they aren't sent notices.
+    versioned: AtomicBool,
+}
+
+/// Returns true if the message has a `version`, valid or not, as sent by clients of the versioned
+/// protocol.
+fn is_versioned_message(msg: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(msg).is_ok_and(|value| value.get("version").is_some())
+}
+
+/// Waits for the next tick of the ping interval, or forever if pings are disabled.
+async fn next_ping(ping: &mut Option<Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Size of the payload of a frame.
+fn frame_len(msg: &Message) -> usize {
+    match msg {
+        Message::Text(text) => text.len(),
+        Message::Binary(bytes) => bytes.len(),
+        _ => 0,
+    }
+}
+
+/// Sends a close frame, unless the client doesn't read it within the given time.
+async fn close(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    code: u16,
+    reason: &'static str,
+    limit: Duration,
+) {
+    let close = ws_tx.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })));
+    let _ = timeout(limit, close).await;
+}
+
+/// Sends the items of the client's queue until it is closed or the connection fails.
+///
+/// Also pings the client, and disconnects it if it doesn't answer in time or doesn't subscribe in
+/// time. Any frame received after a ping counts as an answer. A client that doesn't read a frame
+/// within the pong timeout is disconnected too, so that a full socket can't block the writer. When
+/// the broker shuts down, clients of the versioned protocol are told when to reconnect before being
+/// disconnected.
+async fn write_events(
+    mut ws_tx: SplitSink<WebSocket, Message>,
+    queue: Arc<ClientQueue>,
+    framing: Framing,
+    state: Arc<AppState>,
+    activity: Arc<ClientActivity>,
+    transport: Arc<TransportMetrics>,
+) {
+    let Framing {
+        encoding,
+        batching,
+        compression,
+    } = framing;
+    // Compresses a frame if the client asked for it, recording the bytes saved.
+    let compress = |msg: Message| match compression {
+        Some(level) => {
+            let bytes = frame_len(&msg);
+            let msg = deflate(msg, level);
+            transport.compressed(bytes, frame_len(&msg));
+            msg
+        }
+        None => msg,
+    };
+    let config = &state.client_config;
+    let mut ping = config
+        .ping_interval
+        .map(|period| interval_at(Instant::now() + period, period));
+    // When the current ping has to be answered by, and the number of frames received before it.
+    let mut pong_deadline: Option<(Instant, u64)> = None;
+    // When the client has to be subscribed by, until it is checked.
+    let mut idle_deadline = config.idle_timeout.map(|idle| Instant::now() + idle);
+    // An item popped while batching, to send after the batch.
+    let mut next = None;
+    loop {
+        let item = match next.take() {
+            Some(item) => item,
+            None => tokio::select! {
+                item = queue.pop() => item,
+                _ = next_ping(&mut ping) => {
+                    if pong_deadline.is_none() {
+                        let frames = activity.frames.load(Ordering::Relaxed);
+                        pong_deadline = Some((Instant::now() + config.pong_timeout, frames));
+                    }
+                    match timeout(config.pong_timeout, ws_tx.send(Message::Ping(vec![]))).await {
+                        Ok(Ok(())) => continue,
+                        Ok(Err(e)) => warn!("Could not ping user: {}, closing connection.", e),
+                        Err(_) => {
+                            warn!("Client did not read ping, closing connection.");
+                            close(
+                                &mut ws_tx,
+                                PONG_TIMEOUT_CLOSE_CODE,
+                                "Send timeout.",
+                                config.pong_timeout,
+                            )
+                            .await;
+                        }
+                    }
+                    break;
+                }
+                _ = sleep_until(pong_deadline.map_or_else(Instant::now, |(deadline, _)| deadline)),
+                    if pong_deadline.is_some() =>
+                {
+                    let (_, frames) = pong_deadline.take().unwrap();
+                    if activity.frames.load(Ordering::Relaxed) == frames {
+                        warn!("Client did not answer ping, closing connection.");
+                        close(
+                            &mut ws_tx,
+                            PONG_TIMEOUT_CLOSE_CODE,
+                            "Pong timeout.",
+                            config.pong_timeout,
+                        )
+                        .await;
+                        break;
+                    }
+                    continue;
+                }
+                reconnect_after = state.going_away() => {
+                    // Older clients only get the close frame, as they don't expect notices.
+                    if activity.versioned.load(Ordering::Relaxed) {
+                        let msg = ServerMessage::GoingAway {
+                            reconnect_after_ms: reconnect_after.as_millis() as u64,
+                        };
+                        let msg = compress(encode_message(&msg, encoding));
+                        let _ = timeout(config.pong_timeout, ws_tx.send(msg)).await;
+                    }
+                    close(
+                        &mut ws_tx,
+                        GOING_AWAY_CLOSE_CODE,
+                        "Going away.",
+                        config.pong_timeout,
+                    )
+                    .await;
                     break;
                 }
-            } else {
-                warn!("Message sent by client is not text, closing connection.");
+                _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)),
+                    if idle_deadline.is_some() =>
+                {
+                    if !activity.subscribed.load(Ordering::Relaxed) {
+                        warn!("Client did not subscribe, closing connection.");
+                        close(
+                            &mut ws_tx,
+                            IDLE_CLOSE_CODE,
+                            "Idle timeout.",
+                            config.pong_timeout,
+                        )
+                        .await;
+                        break;
+                    }
+                    idle_deadline = None;
+                    continue;
+                }
+            },
+        };
+        // Events sent in the frame.
+        let mut events = vec![];
+        let msg = match item {
+            QueueItem::Event(item) => match batching {
+                Some(batching) => {
+                    let (batch, item) = collect_batch(item, &queue, batching).await;
+                    next = item;
+                    events = batch;
+                    encode_batch(&events, encoding)
+                }
+                None => {
+                    let msg = encode_event(&item, encoding);
+                    events.push(item);
+                    msg
+                }
+            },
+            QueueItem::Reply(msg) => encode_message(&msg, encoding),
+            QueueItem::Lagged(dropped) => {
+                warn!("Dropped {dropped} messages due to lag.");
+                transport.lagged(dropped);
+                if !activity.versioned.load(Ordering::Relaxed) {
+                    continue;
+                }
+                encode_message(&ServerMessage::Lagged { dropped }, encoding)
+            }
+            QueueItem::Overflowed => {
+                warn!("Client is too slow, closing connection.");
+                close(
+                    &mut ws_tx,
+                    SLOW_CONSUMER_CLOSE_CODE,
+                    "Slow consumer.",
+                    config.pong_timeout,
+                )
+                .await;
+                break;
+            }
+            QueueItem::Closed => break,
+        };
+        let msg = compress(msg);
+        let bytes = frame_len(&msg);
+        match timeout(config.pong_timeout, ws_tx.send(msg)).await {
+            Ok(Ok(())) => {
+                debug!("Sent message.");
+                transport.sent(bytes, &events);
+            }
+            Ok(Err(e)) => {
+                warn!("Could not send event to user: {}, closing connection.", e);
+                break;
+            }
+            Err(_) => {
+                warn!("Client did not read in time, closing connection.");
+                close(
+                    &mut ws_tx,
+                    PONG_TIMEOUT_CLOSE_CODE,
+                    "Send timeout.",
+                    config.pong_timeout,
+                )
+                .await;
                 break;
             }
         }
-        let _ = ws_tx2.write().await.close().await;
-        warn!("Connection ended.");
-    };
+    }
+    let _ = timeout(config.pong_timeout, ws_tx.close()).await;
+}
+
+async fn handle_websocket(socket: WebSocket, state: Arc<AppState>, framing: Framing) {
+    info!("New websocket connection.");
+
+    let (ws_tx, mut ws_rx) = socket.split();
+
+    let queue = Arc::new(ClientQueue::new(
+        state.client_config.queue_size,
+        state.client_config.slow_consumer_policy,
+    ));
+    let activity = Arc::new(ClientActivity::default());
+    let transport = metrics().transport("ws");
+    let _client = transport.connect();
+    let mut writer = tokio::spawn(write_events(
+        ws_tx,
+        queue.clone(),
+        framing,
+        state.clone(),
+        activity.clone(),
+        transport.clone(),
+    ));
+
+    let connection = state.router.connect(queue.clone());
 
-    let t = async move {
-        let sub = sub.clone();
-        loop {
-            let mut r = rx.recv().await;
-            while matches!(r, Err(RecvError::Lagged(_))) {
-                warn!("Messages dropped due to lag.");
-                r = rx.recv().await;
+    let max_subscription_size = state.client_config.max_subscription_size;
+    let queue2 = queue.clone();
+    let r = async move {
+        let mut sub = None;
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            activity.frames.fetch_add(1, Ordering::Relaxed);
+            let msg = match msg {
+                Message::Text(msg) => Ok(msg),
+                Message::Binary(msg) => String::from_utf8(msg).map_err(|_| {
+                    ClientError::new(None, ErrorCode::BadJson, "Message is not valid UTF-8.")
+                }),
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            if let Ok(msg) = &msg {
+                // Only checked until the client is known to use the versioned protocol.
+                if !activity.versioned.load(Ordering::Relaxed) && is_versioned_message(msg) {
+                    activity.versioned.store(true, Ordering::Relaxed);
+                }
             }
-            if let Ok(item) = r {
-                let s = sub.read().await;
-                if let Some(s) = &*s {
-                    if is_match(s, &item) {
-                        let item_str = serde_json::to_string(&item).unwrap();
-                        if let Err(e) = ws_tx.write().await.send(Message::Text(item_str)).await {
-                            warn!("Could not send event to user: {}, closing connection.", e);
-                            break;
-                        } else {
-                            debug!("Sent message.")
-                        }
-                    }
+            let result = msg
+                .and_then(|msg| apply_message(&msg, &mut sub, &connection, max_subscription_size));
+            if result.is_ok() && sub.is_some() {
+                activity.subscribed.store(true, Ordering::Relaxed);
+            }
+            match result {
+                Ok((ack, true)) => queue2.push_reply(ack),
+                // Older clients don't expect acknowledgements.
+                Ok((_, false)) => {}
+                Err(e) if activity.versioned.load(Ordering::Relaxed) => {
+                    queue2.push_reply(ServerMessage::Error(e))
                 }
-            } else {
-                error!("Got unexpected error: {}.", r.unwrap_err());
-                break;
+                // Nor errors, so they are disconnected as they were before errors were sent.
+                Err(_) => break,
             }
         }
-        let _ = ws_tx.write().await.close().await;
+        warn!("Connection ended.");
     };
 
-    tokio::select! {
-        _ = t => {}
-        _ = r => {}
+    let writer_finished = tokio::select! {
+        _ = &mut writer => true,
+        _ = r => false,
     };
 
+    // Stop the writer, which then closes the connection.
+    queue.close();
+    if !writer_finished {
+        let _ = writer.await;
+    }
+
     info!("Websocket connection closed.");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    fn inflate(msg: &Message) -> Vec<u8> {
+        let Message::Binary(bytes) = msg else {
+            panic!("Expected a binary frame, got {msg:?}.");
+        };
+        let mut payload = vec![];
+        DeflateDecoder::new(bytes.as_slice())
+            .read_to_end(&mut payload)
+            .unwrap();
+        payload
+    }
+
+    /// Sizes of the messages of a stream once compressed, one at a time and in batches.
+    fn compressed_sizes(messages: &[&str], level: flate2::Compression) -> (usize, usize) {
+        let single = messages
+            .iter()
+            .map(|msg| frame_len(&deflate(Message::Text(msg.to_string()), level)))
+            .sum();
+        let batched = messages
+            .chunks(DEFAULT_BATCH_SIZE)
+            .map(|batch| {
+                frame_len(&deflate(
+                    Message::Text(format!("[{}]", batch.join(","))),
+                    level,
+                ))
+            })
+            .sum();
+        (single, batched)
+    }
+
+    #[test]
+    fn test_deflate() {
+        let level = flate2::Compression::default();
+        let json =
+            r#"[{"Swap":{"market_id":"1"},"sequence":1},{"Swap":{"market_id":"1"},"sequence":2}]"#;
+        let msg = deflate(Message::Text(json.to_string()), level);
+        assert!(frame_len(&msg) < json.len());
+        assert_eq!(inflate(&msg), json.as_bytes());
+
+        let cbor = Encoding::Cbor.encode(&ServerMessage::Lagged { dropped: 3 });
+        assert_eq!(
+            inflate(&deflate(Message::Binary(cbor.clone()), level)),
+            cbor
+        );
+
+        assert!(matches!(
+            deflate(Message::Ping(vec![]), level),
+            Message::Ping(_)
+        ));
+    }
+
+    /// Measures the savings on a sample stream, see Compression in the README.
+    #[test]
+    fn measure_compression() {
+        let stream = include_str!("../../testdata/stream.jsonl");
+        let messages: Vec<&str> = stream.lines().filter(|line| !line.is_empty()).collect();
+        let bytes: usize = messages.iter().map(|msg| msg.len()).sum();
+        let saved = |compressed: usize| 100.0 * (1.0 - compressed as f64 / bytes as f64);
+        println!("{} messages, {bytes} bytes.", messages.len());
+        for level in [1, 6, 9] {
+            let (single, batched) = compressed_sizes(&messages, flate2::Compression::new(level));
+            println!(
+                "Level {level}: {single} bytes ({:.1}% saved) one message at a time, \
+                 {batched} bytes ({:.1}% saved) in batches of {DEFAULT_BATCH_SIZE}.",
+                saved(single),
+                saved(batched),
+            );
+            assert!(
+                saved(single) > 40.0,
+                "Saved less than expected one message at a time."
+            );
+            assert!(
+                batched < single,
+                "Batches compressed worse than single messages."
+            );
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, sync::Arc};
+
+use processor::emojicoin_dot_fun::{EmojicoinDbEvent, Period};
+
+use crate::{types::SequencedEvent, util::get_market_id};
+
+/// Most recent state of each market, sent to clients when they subscribe so that they don't have
+/// to fetch it from the REST API before the live stream is useful.
+///
+/// Candlesticks are replaced as they are updated, so the one kept for each period is the open one.
+#[derive(Default)]
+pub struct LatestState {
+    global_state: Option<Arc<SequencedEvent>>,
+    market_latest_states: HashMap<u64, Arc<SequencedEvent>>,
+    candlesticks: HashMap<(u64, Period), Arc<SequencedEvent>>,
+    arena_candlesticks: HashMap<Period, Arc<SequencedEvent>>,
+}
+
+impl LatestState {
+    /// Keeps the event if it is a state event.
+    pub fn update(&mut self, item: &Arc<SequencedEvent>) {
+        match &item.event {
+            EmojicoinDbEvent::GlobalState(_) => {
+                self.global_state = Some(item.clone());
+            }
+            EmojicoinDbEvent::MarketLatestState(_) => {
+                if let Ok(market_id) = get_market_id(&item.event) {
+                    self.market_latest_states.insert(market_id, item.clone());
+                }
+            }
+            EmojicoinDbEvent::Candlestick(candle) => {
+                if let Ok(market_id) = get_market_id(&item.event) {
+                    self.candlesticks
+                        .insert((market_id, candle.period), item.clone());
+                }
+            }
+            EmojicoinDbEvent::ArenaCandlestick(candle) => {
+                self.arena_candlesticks.insert(candle.period, item.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the kept events, in order.
+    pub fn events(&self) -> Vec<Arc<SequencedEvent>> {
+        let mut events: Vec<_> = self
+            .global_state
+            .iter()
+            .chain(self.market_latest_states.values())
+            .chain(self.candlesticks.values())
+            .chain(self.arena_candlesticks.values())
+            .cloned()
+            .collect();
+        events.sort_by_key(|e| e.sequence);
+        events
+    }
+}
@@ -1,19 +1,11 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, fmt, str::FromStr, sync::OnceLock, time::Instant};
 
-use processor::emojicoin_dot_fun::{EmojicoinDbEventType, Period};
-use serde::{Deserialize, Serialize};
-use strum::{Display, EnumIter, EnumString};
+use processor::emojicoin_dot_fun::{EmojicoinDbEvent, EmojicoinDbEventType, Period};
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
+use serde_json::Value;
+use strum::{Display, EnumString, IntoStaticStr};
 
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter, PartialEq, Eq, Display)]
-pub enum EventType {
-    Chat,
-    Swap,
-    Liquidity,
-    State,
-    GlobalState,
-    PeriodicState,
-    MarketRegistration,
-}
+use crate::HealthStatus;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(tag = "action", rename_all = "snake_case")]
@@ -29,29 +21,723 @@ pub enum MarketPeriodRequest {
     Unsubscribe { market_id: u64, period: Period },
 }
 
+/// Every candlestick period.
+pub const ALL_PERIODS: [Period; 8] = [
+    Period::FifteenSeconds,
+    Period::OneMinute,
+    Period::FiveMinutes,
+    Period::FifteenMinutes,
+    Period::ThirtyMinutes,
+    Period::OneHour,
+    Period::FourHours,
+    Period::OneDay,
+];
+
+/// Candlesticks of several markets and periods.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MarketPeriods {
+    /// Market IDs, or the markets listed in the subscription if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market_ids: Option<Vec<u64>>,
+    /// Periods, or all periods if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub periods: Option<Vec<Period>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum MarketPeriodsRequest {
+    Subscribe(MarketPeriods),
+    Unsubscribe(MarketPeriods),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ArenaPeriodsRequest {
+    /// Subscribe to the periods, or to all periods if not set.
+    Subscribe {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        periods: Option<Vec<Period>>,
+    },
+    /// Unsubscribe from the periods, or from all periods if not set.
+    Unsubscribe {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        periods: Option<Vec<Period>>,
+    },
+}
+
+/// A market, by ID or by symbol.
+///
+/// Symbols are resolved to market IDs with the markets registered so far, see
+/// [`crate::registry::SymbolRegistry`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged, from = "MarketRefJson")]
+pub enum MarketRef {
+    Id(u64),
+    /// Emojis, e.g. `"🧧"`, or symbol bytes in hex, e.g. `"0xf09fa7a7"`.
+    Symbol(String),
+    /// Symbol bytes, e.g. `[240, 159, 167, 167]`.
+    SymbolBytes(Vec<u8>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarketRefJson {
+    Id(u64),
+    Symbol(String),
+    SymbolBytes(Vec<u8>),
+}
+
+impl From<MarketRefJson> for MarketRef {
+    fn from(value: MarketRefJson) -> Self {
+        match value {
+            MarketRefJson::Id(market_id) => MarketRef::Id(market_id),
+            // Query parameters are always strings.
+            MarketRefJson::Symbol(symbol) => match symbol.parse() {
+                Ok(market_id) => MarketRef::Id(market_id),
+                Err(_) => MarketRef::Symbol(symbol),
+            },
+            MarketRefJson::SymbolBytes(bytes) => MarketRef::SymbolBytes(bytes),
+        }
+    }
+}
+
+impl MarketRef {
+    /// The market ID, if the market is given by ID or its symbol was resolved.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            MarketRef::Id(market_id) => Some(*market_id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MarketRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarketRef::Id(market_id) => write!(f, "{market_id}"),
+            MarketRef::Symbol(symbol) => write!(f, "{symbol}"),
+            MarketRef::SymbolBytes(bytes) => {
+                write!(f, "0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
+
+impl From<u64> for MarketRef {
+    fn from(market_id: u64) -> Self {
+        MarketRef::Id(market_id)
+    }
+}
+
+/// Side of a swap.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Conditions on the values of swap and liquidity events, which other events are not affected by.
+///
+/// Each condition is only checked if it is set.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValueFilter {
+    /// Minimum quote volume of swaps, or quote amount of liquidity events, in octas.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_quote_volume: Option<u64>,
+    /// Minimum base volume of swaps, or base amount of liquidity events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_base_volume: Option<u64>,
+    /// Side of swaps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<Side>,
+    /// Whether swaps result in a state transition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_transition: Option<bool>,
+    /// Integrator address of swaps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrator: Option<String>,
+}
+
+impl ValueFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
 pub struct SubscriptionMessage {
+    /// Markets by ID or symbol.
     #[serde(default)]
-    pub markets: Vec<u64>,
+    pub markets: Vec<MarketRef>,
     #[serde(default)]
     pub event_types: Vec<EmojicoinDbEventType>,
+    /// Only receive events involving these accounts, for events that involve one.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Only receive the swap and liquidity events matching these conditions.
+    #[serde(default)]
+    pub filters: ValueFilter,
+    /// Receive at most one state event per market and period every this many milliseconds, always
+    /// the newest one.
+    #[serde(default)]
+    pub conflation_ms: Option<u64>,
     #[serde(default)]
     pub market_period: Option<MarketPeriodRequest>,
     #[serde(default)]
     pub arena: bool,
     #[serde(default)]
     pub arena_period: Option<ArenaPeriodRequest>,
+    /// Candlestick periods to subscribe to or unsubscribe from, applied after `market_period`.
+    #[serde(default)]
+    pub market_period_requests: Vec<MarketPeriodsRequest>,
+    /// Arena candlestick periods to subscribe to or unsubscribe from, applied after
+    /// `arena_period`.
+    #[serde(default)]
+    pub arena_period_requests: Vec<ArenaPeriodsRequest>,
+    /// Broker sequence number after which buffered events matching the subscription are replayed.
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Epoch of the broker that assigned the `since` sequence number.
+    #[serde(default)]
+    pub epoch: Option<u64>,
+    /// Whether to send the latest state events newly covered by the subscription.
+    #[serde(default)]
+    pub snapshot: bool,
+}
+
+/// A market candlestick subscription in SSE query parameters, e.g. `12:OneHour`, or `12` for all
+/// periods of market 12.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct CandleParam {
+    pub market_id: u64,
+    pub period: Option<Period>,
+}
+
+impl TryFrom<String> for CandleParam {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (market_id, period) = match value.split_once(':') {
+            Some((market_id, period)) => (market_id, Some(period)),
+            None => (value.as_str(), None),
+        };
+        let market_id = market_id
+            .parse()
+            .map_err(|_| format!("Invalid market ID in candles: {value}."))?;
+        let period = period
+            .map(|period| {
+                Period::deserialize(
+                    IntoDeserializer::<serde::de::value::Error>::into_deserializer(period),
+                )
+                .map_err(|_| format!("Unknown period in candles: {value}."))
+            })
+            .transpose()?;
+        Ok(Self { market_id, period })
+    }
+}
+
+/// Subscription of an SSE client, taken from flat query parameters, e.g.
+/// `/sse?markets=12&candles=12:OneHour&arena=true&arena_candles=FifteenSeconds`.
+///
+/// Like in a [`SubscriptionMessage`], empty `markets` or `event_types` mean all.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct SseSubscriptionQuery {
+    /// Markets by ID or symbol.
+    #[serde(default)]
+    pub markets: Vec<MarketRef>,
+    #[serde(default)]
+    pub event_types: Vec<EmojicoinDbEventType>,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// See [`ValueFilter`], whose fields can't be flattened in query parameters.
+    #[serde(default)]
+    pub min_quote_volume: Option<u64>,
+    #[serde(default)]
+    pub min_base_volume: Option<u64>,
+    #[serde(default)]
+    pub side: Option<Side>,
+    #[serde(default)]
+    pub state_transition: Option<bool>,
+    #[serde(default)]
+    pub integrator: Option<String>,
+    #[serde(default)]
+    pub conflation_ms: Option<u64>,
+    #[serde(default)]
+    pub candles: Vec<CandleParam>,
+    #[serde(default)]
+    pub arena: bool,
+    #[serde(default)]
+    pub arena_candles: Vec<Period>,
+    /// Position after which buffered events matching the subscription are replayed, e.g.
+    /// `1760000000000:1234`.
+    #[serde(default)]
+    pub since: Option<Since>,
+    /// Whether to send the latest state events covered by the subscription.
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Whether to name each SSE event after its type instead of sending unnamed `message` events.
+    #[serde(default)]
+    pub named_events: bool,
+}
+
+/// Encoding of the messages sent to a websocket client.
+#[cfg_attr(not(feature = "ws"), allow(dead_code))]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// MessagePack, with structs encoded as maps.
+    Msgpack,
+    Cbor,
+}
+
+/// Compression of the messages sent to a websocket client, which it has to ask for.
+#[cfg_attr(not(feature = "ws"), allow(dead_code))]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Each message is compressed on its own with raw DEFLATE, and sent as a binary frame.
+    Deflate,
+}
+
+#[cfg_attr(not(feature = "ws"), allow(dead_code))]
+impl Encoding {
+    /// Serializes a value in this encoding.
+    pub fn encode(self, value: &impl Serialize) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).unwrap(),
+            Encoding::Msgpack => rmp_serde::to_vec_named(value).unwrap(),
+            Encoding::Cbor => {
+                let mut bytes = vec![];
+                ciborium::into_writer(value, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    /// Encodes an array of values that are already encoded, without decoding them.
+    pub fn encode_array(self, values: &[&[u8]]) -> Vec<u8> {
+        let len = u32::try_from(values.len()).unwrap();
+        let mut bytes = match self {
+            Encoding::Json => {
+                let mut bytes = values.join(&b","[..]);
+                bytes.insert(0, b'[');
+                bytes.push(b']');
+                return bytes;
+            }
+            // Array headers, followed by the values.
+            Encoding::Msgpack => match len {
+                0..=15 => vec![0x90 | len as u8],
+                16..=0xffff => [&[0xdc][..], &(len as u16).to_be_bytes()].concat(),
+                _ => [&[0xdd][..], &len.to_be_bytes()].concat(),
+            },
+            Encoding::Cbor => match len {
+                0..=23 => vec![0x80 | len as u8],
+                24..=0xff => vec![0x98, len as u8],
+                0x100..=0xffff => [&[0x99][..], &(len as u16).to_be_bytes()].concat(),
+                _ => [&[0x9a][..], &len.to_be_bytes()].concat(),
+            },
+        };
+        for value in values {
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+}
+
+/// Options of a websocket connection, taken from the query parameters of the upgrade request, e.g.
+/// `/?batch_ms=50&batch_size=200`.
+#[cfg_attr(not(feature = "ws"), allow(dead_code))]
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct WsConnectionQuery {
+    /// Encoding of the messages sent to the client, which takes precedence over the subprotocol.
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+    /// Send events in JSON array frames, accumulated for up to this many milliseconds.
+    #[serde(default)]
+    pub batch_ms: Option<u64>,
+    /// Maximum number of events in a batch.
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Compress the messages sent to the client, if the broker allows it.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+/// Version of the client message protocol, see [`SubscriptionRequest`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A market and candlestick period.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarketPeriod {
+    pub market_id: u64,
+    pub period: Period,
+}
+
+/// Part of a subscription, for each of its dimensions.
+///
+/// Unlike in a [`SubscriptionMessage`], an empty list means none, and all markets or event types
+/// must be requested explicitly.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionFilter {
+    #[serde(default)]
+    pub all_markets: bool,
+    /// Markets by ID or symbol.
+    #[serde(default)]
+    pub markets: Vec<MarketRef>,
+    #[serde(default)]
+    pub all_event_types: bool,
+    #[serde(default)]
+    pub event_types: Vec<EmojicoinDbEventType>,
+    /// Accounts that events involving an account must involve. Unlike other lists, an empty one
+    /// means that events aren't filtered by account.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+    /// Conditions on swap and liquidity events. When subscribing, the conditions that are set
+    /// replace the current ones, and when unsubscribing, they are removed.
+    #[serde(default, skip_serializing_if = "ValueFilter::is_empty")]
+    pub filters: ValueFilter,
+    /// Conflation window of state events in milliseconds. When unsubscribing, any value disables
+    /// conflation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflation_ms: Option<u64>,
+    #[serde(default)]
+    pub market_periods: Vec<MarketPeriod>,
+    /// Candlesticks of several markets and periods, added to `market_periods`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candlesticks: Vec<MarketPeriods>,
+    #[serde(default)]
+    pub arena: bool,
+    #[serde(default)]
+    pub arena_periods: Vec<Period>,
+}
+
+/// Change to the current subscription requested by a client.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SubscriptionAction {
+    /// Add the filter to the current subscription.
+    Subscribe(SubscriptionFilter),
+    /// Remove the filter from the current subscription.
+    Unsubscribe(SubscriptionFilter),
+    /// Replace the current subscription with the filter.
+    Replace(SubscriptionFilter),
+    /// Remove the current subscription.
+    Clear,
 }
 
+/// A client message of the versioned protocol, e.g.
+/// `{ "version": 1, "action": "subscribe", "markets": [1], "all_event_types": true }`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SubscriptionRequest {
+    pub version: u32,
+    #[serde(flatten)]
+    pub action: SubscriptionAction,
+    /// Broker sequence number after which buffered events matching the subscription are replayed.
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Epoch of the broker that assigned the `since` sequence number.
+    #[serde(default)]
+    pub epoch: Option<u64>,
+    /// Whether to send the latest state events newly covered by the subscription.
+    #[serde(default)]
+    pub snapshot: bool,
+}
+
+/// A message sent by a websocket client, along with its optional request id, which can be any JSON
+/// value and is included in the reply.
 #[derive(Debug, PartialEq, Eq)]
+pub struct ClientRequest {
+    pub id: Option<Value>,
+    pub message: ClientMessage,
+}
+
+/// A message sent by a websocket client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// A message without a `version`, as sent before the protocol was versioned.
+    Legacy(SubscriptionMessage),
+    V1(SubscriptionRequest),
+}
+
+impl ClientMessage {
+    pub fn since(&self) -> Option<Since> {
+        let (sequence, epoch) = match self {
+            ClientMessage::Legacy(msg) => (msg.since, msg.epoch),
+            ClientMessage::V1(request) => (request.since, request.epoch),
+        };
+        sequence.map(|sequence| Since { epoch, sequence })
+    }
+
+    pub fn snapshot(&self) -> bool {
+        match self {
+            ClientMessage::Legacy(msg) => msg.snapshot,
+            ClientMessage::V1(request) => request.snapshot,
+        }
+    }
+
+    /// The markets listed in the message, if it can list any.
+    pub fn markets_mut(&mut self) -> Option<&mut Vec<MarketRef>> {
+        match self {
+            ClientMessage::Legacy(msg) => Some(&mut msg.markets),
+            ClientMessage::V1(SubscriptionRequest {
+                action:
+                    SubscriptionAction::Subscribe(filter)
+                    | SubscriptionAction::Unsubscribe(filter)
+                    | SubscriptionAction::Replace(filter),
+                ..
+            }) => Some(&mut filter.markets),
+            ClientMessage::V1(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ClientSubscription {
+    /// Whether events on all markets are covered, regardless of `markets`.
+    pub all_markets: bool,
     pub markets: HashSet<u64>,
+    /// Whether all event types are covered, regardless of `event_types`.
+    pub all_event_types: bool,
     pub event_types: HashSet<EmojicoinDbEventType>,
+    /// Normalized account addresses that events involving an account must involve, if any.
+    pub addresses: HashSet<String>,
+    /// Conditions on swap and liquidity events, with a normalized integrator address.
+    pub filters: ValueFilter,
+    /// Conflation window of state events in milliseconds, see [`crate::queue::ClientQueue`].
+    pub conflation_ms: Option<u64>,
     pub market_candlestick_periods: HashSet<(u64, Period)>,
     pub arena: bool,
     pub arena_candlestick_periods: HashSet<Period>,
 }
 
+impl ClientSubscription {
+    /// Number of markets, event types, addresses and candlestick periods listed in the subscription.
+    pub fn size(&self) -> usize {
+        self.markets.len()
+            + self.event_types.len()
+            + self.addresses.len()
+            + self.market_candlestick_periods.len()
+            + self.arena_candlestick_periods.len()
+    }
+}
+
+/// The part of an event that determines which subscriptions cover it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RoutingKey {
+    /// Arena events other than candlesticks, covered by the `arena` flag.
+    Arena,
+    ArenaCandlestick(Period),
+    Candlestick(u64, Period),
+    /// Events that aren't tied to a market, only filtered by event type.
+    Global(EmojicoinDbEventType),
+    /// Market events, filtered by event type and market ID.
+    Market(EmojicoinDbEventType, u64),
+}
+
+/// Position in the broker's event stream after which to replay buffered events.
+///
+/// Sequence numbers start over when the broker restarts, so a position is only meaningful along
+/// with the epoch of the broker process that assigned it. Written `epoch:sequence`, e.g. in SSE
+/// event IDs, or `sequence` for clients that don't know the epoch.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Since {
+    pub epoch: Option<u64>,
+    pub sequence: u64,
+}
+
+impl FromStr for Since {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid position: {s}.");
+        let (epoch, sequence) = match s.split_once(':') {
+            Some((epoch, sequence)) => (Some(epoch.parse().map_err(|_| invalid())?), sequence),
+            None => (None, s),
+        };
+        let sequence = sequence.parse().map_err(|_| invalid())?;
+        Ok(Self { epoch, sequence })
+    }
+}
+
+impl TryFrom<String> for Since {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// An event as sent to clients, tagged with the broker epoch and sequence number it was assigned.
+///
+/// It is serialized once when published and shared by all clients, so that delivering it to a
+/// client only takes a filter check and a copy of the JSON. Binary encodings are only serialized
+/// once a client needs them.
+#[derive(Debug)]
+pub struct SequencedEvent {
+    pub event: EmojicoinDbEvent,
+    /// When the broker process that assigned the sequence number started, in milliseconds since
+    /// the Unix epoch.
+    pub epoch: u64,
+    pub sequence: u64,
+    /// The serialized event, e.g. `{ "Swap": { ... }, "epoch": 1760000000000, "sequence": 42 }`.
+    pub json: String,
+    /// When the broker received the event, to measure how long it takes to send it to clients.
+    pub received_at: Instant,
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    msgpack: OnceLock<Vec<u8>>,
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    cbor: OnceLock<Vec<u8>>,
+}
+
+/// The event is flattened so that its type remains the first key of the serialized object, in
+/// every encoding.
+#[derive(Serialize)]
+struct SequencedEventJson<'a> {
+    #[serde(flatten)]
+    event: &'a EmojicoinDbEvent,
+    epoch: u64,
+    sequence: u64,
+}
+
+impl SequencedEvent {
+    pub fn new(event: EmojicoinDbEvent, epoch: u64, sequence: u64) -> Self {
+        let json = serde_json::to_string(&SequencedEventJson {
+            event: &event,
+            epoch,
+            sequence,
+        })
+        .unwrap();
+        Self {
+            event,
+            epoch,
+            sequence,
+            json,
+            received_at: Instant::now(),
+            msgpack: OnceLock::new(),
+            cbor: OnceLock::new(),
+        }
+    }
+
+    /// The serialized event in the given encoding.
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    pub fn encoded(&self, encoding: Encoding) -> &[u8] {
+        let cache = match encoding {
+            Encoding::Json => return self.json.as_bytes(),
+            Encoding::Msgpack => &self.msgpack,
+            Encoding::Cbor => &self.cbor,
+        };
+        cache.get_or_init(|| {
+            encoding.encode(&SequencedEventJson {
+                event: &self.event,
+                epoch: self.epoch,
+                sequence: self.sequence,
+            })
+        })
+    }
+}
+
+/// State of the connection to the processor, as reported on `/health`.
+#[derive(Serialize, Debug)]
+pub struct ProcessorHealth {
+    pub status: HealthStatus,
+    /// Milliseconds since the last event was received, if one was.
+    pub since_last_event_ms: Option<u64>,
+    /// Highest transaction version received, if any.
+    pub transaction_version: Option<i64>,
+    /// Milliseconds since the highest transaction version was first received.
+    pub since_progress_ms: Option<u64>,
+    pub reconnects: u64,
+    /// Reconnections left before the broker gives up if they keep failing.
+    pub retries_left: u64,
+}
+
+/// Body of the response to `/health`.
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub processor: ProcessorHealth,
+    /// Number of connected clients.
+    pub clients: usize,
+    /// Number of events waiting to be sent to clients.
+    pub queued_events: usize,
+}
+
+/// Messages sent by the broker to clients, other than events.
+///
+/// Their keys are in snake case, so that they can't be confused with events.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ServerMessage {
+    /// Events matching the subscription were dropped because the client was too slow.
+    Lagged { dropped: u64 },
+    /// Some events after the requested `since` sequence number can't be replayed, as they are no
+    /// longer buffered or the broker restarted since.
+    Truncated { since: u64 },
+    /// A client message was applied, resulting in the given subscription.
+    Ack {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        subscription: Option<Box<SubscriptionFilter>>,
+        /// Market symbols of the message that aren't registered, and were ignored.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        unknown_symbols: Vec<String>,
+    },
+    /// A client message could not be applied, and the subscription was left unchanged.
+    Error(ClientError),
+    /// Identifies an SSE connection, so that its subscription can be changed.
+    Connection { id: String },
+    /// The broker is shutting down, and the client should reconnect after the given delay.
+    GoingAway { reconnect_after_ms: u64 },
+}
+
+/// Why a client message could not be applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message is not valid JSON.
+    BadJson,
+    /// The message is valid JSON, but not a valid client message.
+    InvalidMessage,
+    UnknownPeriod,
+    UnknownEventType,
+    /// An address is not a hex account address starting with `0x`.
+    InvalidAddress,
+    UnsupportedVersion,
+    /// The resulting subscription would be larger than allowed.
+    LimitExceeded,
+    /// The client asked for compression, which the broker doesn't allow.
+    CompressionDisabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ClientError {
+    /// The request id of the message, if it could be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ClientError {
+    pub fn new(id: Option<Value>, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            id,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -64,11 +750,19 @@ pub mod tests {
             )
             .unwrap(),
             SubscriptionMessage {
-                markets: vec![1, 2, 3],
+                markets: vec![1.into(), 2.into(), 3.into()],
                 event_types: vec![EmojicoinDbEventType::Chat],
+                addresses: vec![],
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_period: None,
                 arena: true,
                 arena_period: None,
+                market_period_requests: vec![],
+                arena_period_requests: vec![],
+                since: None,
+                epoch: None,
+                snapshot: false,
             },
         );
 
@@ -83,13 +777,21 @@ pub mod tests {
             )
             .unwrap(),
             SubscriptionMessage {
-                markets: vec![4, 2],
+                markets: vec![4.into(), 2.into()],
                 event_types: vec![EmojicoinDbEventType::MarketRegistration],
+                addresses: vec![],
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_period: None,
                 arena: false,
                 arena_period: Some(ArenaPeriodRequest::Subscribe {
                     period: Period::FifteenSeconds
                 }),
+                market_period_requests: vec![],
+                arena_period_requests: vec![],
+                since: None,
+                epoch: None,
+                snapshot: false,
             },
         );
 
@@ -99,11 +801,19 @@ pub mod tests {
             )
             .unwrap(),
             SubscriptionMessage {
-                markets: vec![7, 11],
+                markets: vec![7.into(), 11.into()],
                 event_types: vec![EmojicoinDbEventType::Swap],
+                addresses: vec![],
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_period: None,
                 arena: true,
                 arena_period: None,
+                market_period_requests: vec![],
+                arena_period_requests: vec![],
+                since: None,
+                epoch: None,
+                snapshot: false,
             },
         );
     }
@@ -163,6 +873,7 @@ pub mod tests {
     }"#;
         let sub: SubscriptionMessage = serde_json::from_str(json).unwrap();
         assert_eq!(sub.arena_period, None);
+        assert_eq!(sub.since, None);
         assert!(sub.market_period.is_some());
         assert_eq!(
             sub.market_period.unwrap(),
@@ -173,11 +884,165 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn deserialize_subscription_with_since() {
+        let sub: SubscriptionMessage =
+            serde_json::from_str(r#"{ "markets": [1], "since": 1234 }"#).unwrap();
+        assert_eq!(sub.markets, vec![1.into()]);
+        assert_eq!(sub.since, Some(1234));
+        assert_eq!(sub.epoch, None);
+        assert!(!sub.snapshot);
+    }
+
+    #[test]
+    fn parse_since() {
+        assert_eq!(
+            "1760000000000:1234".parse(),
+            Ok(Since {
+                epoch: Some(1760000000000),
+                sequence: 1234,
+            })
+        );
+        assert_eq!(
+            "1234".parse(),
+            Ok(Since {
+                epoch: None,
+                sequence: 1234,
+            })
+        );
+        assert!("abc:1234".parse::<Since>().is_err());
+        assert!("1760000000000:".parse::<Since>().is_err());
+    }
+
+    #[test]
+    fn deserialize_subscription_with_snapshot() {
+        let sub: SubscriptionMessage =
+            serde_json::from_str(r#"{ "markets": [1, 2], "snapshot": true }"#).unwrap();
+        assert_eq!(sub.markets, vec![1.into(), 2.into()]);
+        assert!(sub.snapshot);
+    }
+
+    #[test]
+    fn serialize_server_message() {
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Lagged { dropped: 3 }).unwrap(),
+            r#"{"lagged":{"dropped":3}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Truncated { since: 12 }).unwrap(),
+            r#"{"truncated":{"since":12}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::GoingAway {
+                reconnect_after_ms: 1234
+            })
+            .unwrap(),
+            r#"{"going_away":{"reconnect_after_ms":1234}}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Ack {
+                id: Some(Value::from("a")),
+                subscription: None,
+                unknown_symbols: vec![],
+            })
+            .unwrap(),
+            r#"{"ack":{"id":"a","subscription":null}}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Ack {
+                id: None,
+                subscription: None,
+                unknown_symbols: vec!["🐸".into()],
+            })
+            .unwrap(),
+            r#"{"ack":{"subscription":null,"unknown_symbols":["🐸"]}}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Error(ClientError::new(
+                None,
+                ErrorCode::UnknownPeriod,
+                "Unknown period \"TwoHours\"."
+            )))
+            .unwrap(),
+            r#"{"error":{"code":"unknown_period","message":"Unknown period \"TwoHours\"."}}"#,
+        );
+    }
+
+    #[test]
+    fn serialize_health_report() {
+        let report = HealthReport {
+            processor: ProcessorHealth {
+                status: HealthStatus::Starting,
+                since_last_event_ms: None,
+                transaction_version: None,
+                since_progress_ms: None,
+                reconnects: 0,
+                retries_left: 10,
+            },
+            clients: 2,
+            queued_events: 5,
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "processor": {
+                    "status": "starting",
+                    "since_last_event_ms": null,
+                    "transaction_version": null,
+                    "since_progress_ms": null,
+                    "reconnects": 0,
+                    "retries_left": 10,
+                },
+                "clients": 2,
+                "queued_events": 5,
+            })
+        );
+    }
+
+    #[test]
+    fn encode_arrays() {
+        // Flattened like sequenced events, so that maps of unknown length are encoded too.
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Flattened {
+            #[serde(flatten)]
+            message: ServerMessage,
+            sequence: u64,
+        }
+
+        for len in [0, 1, 20, 300] {
+            let values: Vec<Flattened> = (0..len)
+                .map(|i| Flattened {
+                    message: ServerMessage::Lagged { dropped: i },
+                    sequence: i,
+                })
+                .collect();
+            for encoding in [Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+                let encoded: Vec<Vec<u8>> = values.iter().map(|v| encoding.encode(v)).collect();
+                let encoded: Vec<&[u8]> = encoded.iter().map(Vec::as_slice).collect();
+                let array = encoding.encode_array(&encoded);
+                let decoded: Vec<Flattened> = match encoding {
+                    Encoding::Json => serde_json::from_slice(&array).unwrap(),
+                    Encoding::Msgpack => rmp_serde::from_slice(&array).unwrap(),
+                    Encoding::Cbor => ciborium::from_reader(array.as_slice()).unwrap(),
+                };
+                assert_eq!(decoded, values, "{encoding}");
+            }
+        }
+        assert_eq!("msgpack".parse(), Ok(Encoding::Msgpack));
+    }
+
     #[test]
     fn subscription_idempotent_serialization_happy_path() {
         let sub = SubscriptionMessage {
-            markets: vec![1, 2, 3],
+            markets: vec![1.into(), 2.into(), 3.into()],
             event_types: vec![EmojicoinDbEventType::Chat],
+            addresses: vec![],
+            filters: ValueFilter {
+                min_quote_volume: Some(100_000_000),
+                side: Some(Side::Buy),
+                ..Default::default()
+            },
+            conflation_ms: Some(500),
             arena: true,
             arena_period: Some(ArenaPeriodRequest::Unsubscribe {
                 period: Period::FifteenMinutes,
@@ -186,10 +1051,165 @@ pub mod tests {
                 market_id: 1,
                 period: Period::FourHours,
             }),
+            market_period_requests: vec![],
+            arena_period_requests: vec![],
+            since: Some(12),
+            epoch: Some(1760000000000),
+            snapshot: true,
         };
 
         let json = serde_json::to_string(&sub).unwrap();
         let sub_parsed: SubscriptionMessage = serde_json::from_str(json.as_str()).unwrap();
         assert_eq!(sub, sub_parsed);
     }
+
+    async fn parse_sse_query(query: &str) -> Result<SseSubscriptionQuery, String> {
+        use axum::extract::FromRequestParts;
+
+        let (mut parts, _) = axum::http::Request::builder()
+            .uri(format!("/sse?{query}"))
+            .body(())
+            .unwrap()
+            .into_parts();
+        axum_extra::extract::Query::<SseSubscriptionQuery>::from_request_parts(&mut parts, &())
+            .await
+            .map(|query| query.0)
+            .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn deserialize_sse_query() {
+        assert_eq!(
+            parse_sse_query("markets=1&markets=2&markets=3&event_types=Chat&arena=true")
+                .await
+                .unwrap(),
+            SseSubscriptionQuery {
+                markets: vec![1.into(), 2.into(), 3.into()],
+                event_types: vec![EmojicoinDbEventType::Chat],
+                addresses: vec![],
+                arena: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            parse_sse_query(
+                "markets=4&markets=2&event_types=MarketRegistration&arena_candles=FifteenSeconds"
+            )
+            .await
+            .unwrap(),
+            SseSubscriptionQuery {
+                markets: vec![4.into(), 2.into()],
+                event_types: vec![EmojicoinDbEventType::MarketRegistration],
+                addresses: vec![],
+                arena_candles: vec![Period::FifteenSeconds],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            parse_sse_query("").await.unwrap(),
+            SseSubscriptionQuery::default()
+        );
+
+        assert_eq!(
+            parse_sse_query("named_events=true").await.unwrap(),
+            SseSubscriptionQuery {
+                named_events: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn deserialize_market_symbols() {
+        let sub: SubscriptionMessage =
+            serde_json::from_str(r#"{ "markets": [1, "2", "🧧", "0xf09fa7a7", [240, 159]] }"#)
+                .unwrap();
+        assert_eq!(
+            sub.markets,
+            vec![
+                MarketRef::Id(1),
+                MarketRef::Id(2),
+                MarketRef::Symbol("🧧".into()),
+                MarketRef::Symbol("0xf09fa7a7".into()),
+                MarketRef::SymbolBytes(vec![240, 159]),
+            ]
+        );
+        assert_eq!(
+            parse_sse_query("markets=3&markets=%F0%9F%A7%A7")
+                .await
+                .unwrap()
+                .markets,
+            vec![MarketRef::Id(3), MarketRef::Symbol("🧧".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn deserialize_sse_query_with_candles() {
+        let query = parse_sse_query(
+            "markets=12&candles=12:OneHour&candles=7&arena=true&arena_candles=OneDay&since=5",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            query,
+            SseSubscriptionQuery {
+                markets: vec![12.into()],
+                candles: vec![
+                    CandleParam {
+                        market_id: 12,
+                        period: Some(Period::OneHour),
+                    },
+                    CandleParam {
+                        market_id: 7,
+                        period: None,
+                    },
+                ],
+                arena: true,
+                arena_candles: vec![Period::OneDay],
+                since: Some(Since {
+                    epoch: None,
+                    sequence: 5,
+                }),
+                ..Default::default()
+            },
+        );
+
+        let sub = ClientSubscription::from(query);
+        assert!(sub
+            .market_candlestick_periods
+            .contains(&(12, Period::OneHour)));
+        assert!(!sub
+            .market_candlestick_periods
+            .contains(&(12, Period::OneDay)));
+        assert_eq!(sub.market_candlestick_periods.len(), 1 + ALL_PERIODS.len());
+        assert_eq!(
+            sub.arena_candlestick_periods,
+            HashSet::from([Period::OneDay])
+        );
+        assert!(sub.arena);
+    }
+
+    #[tokio::test]
+    async fn deserialize_sse_query_with_filters() {
+        let query = parse_sse_query("event_types=Swap&min_quote_volume=100&side=buy")
+            .await
+            .unwrap();
+        assert_eq!(
+            ClientSubscription::from(query).filters,
+            ValueFilter {
+                min_quote_volume: Some(100),
+                side: Some(Side::Buy),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn deserialize_sse_query_errors() {
+        assert!(parse_sse_query("candles=12:TwoHours").await.is_err());
+        assert!(parse_sse_query("candles=twelve:OneHour").await.is_err());
+        assert!(parse_sse_query("arena_candles=TwoHours").await.is_err());
+    }
 }
@@ -1,17 +1,21 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, str::FromStr, time::Duration};
 
 use log::error;
 use num_traits::ToPrimitive;
-use processor::emojicoin_dot_fun::{EmojicoinDbEvent, EmojicoinDbEventType};
-use serde_json::Error;
+use processor::emojicoin_dot_fun::{EmojicoinDbEvent, EmojicoinDbEventType, Period};
+use rand::{rngs::OsRng, Rng, RngCore};
+use serde::Deserialize;
+use serde_json::Value;
 use tokio::signal;
 
 use crate::types::{
-    ArenaPeriodRequest, ClientSubscription, MarketPeriodRequest, SubscriptionMessage,
+    ArenaPeriodRequest, ArenaPeriodsRequest, ClientError, ClientMessage, ClientRequest,
+    ClientSubscription, ErrorCode, MarketPeriod, MarketPeriodRequest, MarketPeriods,
+    MarketPeriodsRequest, MarketRef, RoutingKey, Side, SseSubscriptionQuery, SubscriptionAction,
+    SubscriptionFilter, SubscriptionMessage, ValueFilter, ALL_PERIODS, PROTOCOL_VERSION,
 };
 
 /// Get the market ID of a EmojicoinDbEvent of a given EventType
-#[allow(dead_code)]
 pub fn get_market_id(event: &EmojicoinDbEvent) -> Result<u64, String> {
     let market_id = match event {
         EmojicoinDbEvent::Swap(s) => &s.market_id,
@@ -33,62 +37,222 @@ pub fn get_market_id(event: &EmojicoinDbEvent) -> Result<u64, String> {
         .ok_or("Failed to convert BigDecimal to u64".to_string())
 }
 
-/// Returns true if the given subscription should receive the given event.
-#[allow(dead_code)]
-pub fn is_match(subscription: &ClientSubscription, event: &EmojicoinDbEvent) -> bool {
+/// Get the name of the type of a EmojicoinDbEvent, as used as its key when serialized.
+pub fn get_event_name(event: &EmojicoinDbEvent) -> &'static str {
+    match event {
+        EmojicoinDbEvent::Swap(_) => "Swap",
+        EmojicoinDbEvent::Chat(_) => "Chat",
+        EmojicoinDbEvent::MarketRegistration(_) => "MarketRegistration",
+        EmojicoinDbEvent::PeriodicState(_) => "PeriodicState",
+        EmojicoinDbEvent::MarketLatestState(_) => "MarketLatestState",
+        EmojicoinDbEvent::GlobalState(_) => "GlobalState",
+        EmojicoinDbEvent::Liquidity(_) => "Liquidity",
+        EmojicoinDbEvent::Candlestick(_) => "Candlestick",
+        EmojicoinDbEvent::ArenaEnter(_) => "ArenaEnter",
+        EmojicoinDbEvent::ArenaExit(_) => "ArenaExit",
+        EmojicoinDbEvent::ArenaMelee(_) => "ArenaMelee",
+        EmojicoinDbEvent::ArenaSwap(_) => "ArenaSwap",
+        EmojicoinDbEvent::ArenaVaultBalanceUpdate(_) => "ArenaVaultBalanceUpdate",
+        EmojicoinDbEvent::ArenaCandlestick(_) => "ArenaCandlestick",
+    }
+}
+
+/// Get the transaction version of a EmojicoinDbEvent.
+///
+/// For candlesticks, this is the version of the last transaction that updated them.
+pub fn get_transaction_version(event: &EmojicoinDbEvent) -> i64 {
+    match event {
+        EmojicoinDbEvent::Swap(s) => s.transaction_version,
+        EmojicoinDbEvent::Chat(c) => c.transaction_version,
+        EmojicoinDbEvent::MarketRegistration(mr) => mr.transaction_version,
+        EmojicoinDbEvent::PeriodicState(ps) => ps.transaction_version,
+        EmojicoinDbEvent::MarketLatestState(mls) => mls.transaction_version,
+        EmojicoinDbEvent::GlobalState(gs) => gs.transaction_version,
+        EmojicoinDbEvent::Liquidity(l) => l.transaction_version,
+        EmojicoinDbEvent::Candlestick(candle) => candle.last_transaction_version,
+        EmojicoinDbEvent::ArenaEnter(e) => e.transaction_version,
+        EmojicoinDbEvent::ArenaExit(e) => e.transaction_version,
+        EmojicoinDbEvent::ArenaMelee(m) => m.transaction_version,
+        EmojicoinDbEvent::ArenaSwap(s) => s.transaction_version,
+        EmojicoinDbEvent::ArenaVaultBalanceUpdate(vbu) => vbu.transaction_version,
+        EmojicoinDbEvent::ArenaCandlestick(candle) => candle.last_transaction_version,
+    }
+}
+
+/// Get the routing key of a EmojicoinDbEvent, which determines the subscriptions that cover it.
+pub fn get_routing_key(event: &EmojicoinDbEvent) -> Result<RoutingKey, String> {
     let event_type: EmojicoinDbEventType = event.into();
-    match event_type {
-        EmojicoinDbEventType::ArenaEnter
-        | EmojicoinDbEventType::ArenaExit
-        | EmojicoinDbEventType::ArenaMelee
-        | EmojicoinDbEventType::ArenaSwap
-        | EmojicoinDbEventType::ArenaVaultBalanceUpdate => subscription.arena,
-        EmojicoinDbEventType::ArenaCandlestick => {
-            if let EmojicoinDbEvent::ArenaCandlestick(event) = event {
-                subscription
-                    .arena_candlestick_periods
-                    .contains(&event.period)
-            } else {
-                unreachable!("This would only ever be reachable if enums were mapped incorrectly.");
-            }
+    Ok(match event {
+        EmojicoinDbEvent::ArenaEnter(_)
+        | EmojicoinDbEvent::ArenaExit(_)
+        | EmojicoinDbEvent::ArenaMelee(_)
+        | EmojicoinDbEvent::ArenaSwap(_)
+        | EmojicoinDbEvent::ArenaVaultBalanceUpdate(_) => RoutingKey::Arena,
+        EmojicoinDbEvent::ArenaCandlestick(candle) => RoutingKey::ArenaCandlestick(candle.period),
+        EmojicoinDbEvent::GlobalState(_) => RoutingKey::Global(event_type),
+        EmojicoinDbEvent::Candlestick(candle) => {
+            RoutingKey::Candlestick(get_market_id(event)?, candle.period)
         }
-        EmojicoinDbEventType::GlobalState => {
-            subscription.event_types.is_empty() || subscription.event_types.contains(&event_type)
+        _ => RoutingKey::Market(event_type, get_market_id(event)?),
+    })
+}
+
+/// Returns true if the given subscription covers events with the given routing key.
+pub fn is_key_match(subscription: &ClientSubscription, key: &RoutingKey) -> bool {
+    match key {
+        RoutingKey::Arena => subscription.arena,
+        RoutingKey::ArenaCandlestick(period) => {
+            subscription.arena_candlestick_periods.contains(period)
         }
-        EmojicoinDbEventType::Candlestick => {
-            if let EmojicoinDbEvent::Candlestick(event) = &event {
-                subscription.market_candlestick_periods.contains(&(
-                    event
-                        .market_id
-                        .to_u64()
-                        .expect("market_id should be a u64."),
-                    event.period,
-                ))
-            } else {
-                unreachable!("This would only ever be reachable if enums were mapped incorrectly.");
-            }
+        RoutingKey::Candlestick(market_id, period) => subscription
+            .market_candlestick_periods
+            .contains(&(*market_id, *period)),
+        RoutingKey::Global(event_type) => {
+            subscription.all_event_types || subscription.event_types.contains(event_type)
         }
-        _ => {
-            let markets_is_empty = subscription.markets.is_empty();
-            let event_types_is_empty = subscription.event_types.is_empty();
-            if !event_types_is_empty && !subscription.event_types.contains(&event_type) {
+        RoutingKey::Market(event_type, market_id) => {
+            if !subscription.all_event_types && !subscription.event_types.contains(event_type) {
                 return false;
             }
 
-            // At this point, event_types is either empty or it contains the event type.
+            // At this point, all event types are covered or event_types contains the event type.
             // Now just check if the market matches.
-            if markets_is_empty {
-                return true;
-            }
+            subscription.all_markets || subscription.markets.contains(market_id)
+        }
+    }
+}
 
-            match get_market_id(event) {
-                Ok(market_id) => subscription.markets.contains(&market_id),
-                Err(msg) => {
-                    error!("{msg}");
-                    false
-                }
-            }
+// The index of the first piece of actual data in a hex address.
+const FIRST_HEX_CHAR: usize = 2;
+
+// The maximum length of a hex address (0x + 64 chars).
+const MAX_ADDRESS_LENGTH: usize = 66;
+
+/// Normalizes an account address the same way as allowlister3000, by removing leading zeros, so
+/// that `0x0abc` and `0xabc` are the same address. Hex digits are also lowercased.
+pub fn normalize_address(address: &str) -> String {
+    let mut address = address.to_lowercase();
+    loop {
+        if address == "0x" {
+            break String::from("0x0");
+        }
+        if address.starts_with("0x0") {
+            address.remove(FIRST_HEX_CHAR);
+        } else {
+            break address;
+        }
+    }
+}
+
+/// Returns true if the address is a hex address starting with `0x`.
+pub fn is_valid_address(address: &str) -> bool {
+    address.len() <= MAX_ADDRESS_LENGTH
+        && address
+            .strip_prefix("0x")
+            .is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Get the normalized addresses of the accounts involved in a EmojicoinDbEvent, if it involves
+/// any.
+pub fn get_addresses(event: &EmojicoinDbEvent) -> Option<Vec<String>> {
+    let addresses = match event {
+        EmojicoinDbEvent::Swap(swap) => vec![&swap.sender, &swap.swapper],
+        EmojicoinDbEvent::Liquidity(liquidity) => vec![&liquidity.sender, &liquidity.provider],
+        EmojicoinDbEvent::Chat(chat) => vec![&chat.sender, &chat.user],
+        EmojicoinDbEvent::ArenaEnter(enter) => vec![&enter.user],
+        EmojicoinDbEvent::ArenaExit(exit) => vec![&exit.user],
+        EmojicoinDbEvent::ArenaSwap(swap) => vec![&swap.user],
+        _ => return None,
+    };
+    Some(
+        addresses
+            .into_iter()
+            .map(|a| normalize_address(a))
+            .collect(),
+    )
+}
+
+/// Returns true if the given subscription's address filter lets through an event involving the
+/// given accounts, as returned by [`get_addresses`].
+///
+/// Events that don't involve an account are not filtered by address.
+pub fn is_address_match(subscription: &ClientSubscription, addresses: Option<&[String]>) -> bool {
+    subscription.addresses.is_empty()
+        || addresses.map_or(true, |addresses| {
+            addresses
+                .iter()
+                .any(|address| subscription.addresses.contains(address))
+        })
+}
+
+/// Returns true if the value is at least `min`, or if `min` is not set.
+fn is_at_least(value: &impl ToPrimitive, min: Option<u64>) -> bool {
+    min.map_or(true, |min| {
+        value.to_u128().is_some_and(|value| value >= min.into())
+    })
+}
+
+/// Returns true if the given subscription's value filter lets the given event through.
+pub fn is_value_match(subscription: &ClientSubscription, event: &EmojicoinDbEvent) -> bool {
+    let filters = &subscription.filters;
+    match event {
+        EmojicoinDbEvent::Swap(swap) => {
+            is_at_least(&swap.quote_volume, filters.min_quote_volume)
+                && is_at_least(&swap.base_volume, filters.min_base_volume)
+                && filters
+                    .side
+                    .map_or(true, |side| swap.is_sell == (side == Side::Sell))
+                && filters.state_transition.map_or(true, |transition| {
+                    swap.results_in_state_transition == transition
+                })
+                && filters.integrator.as_ref().map_or(true, |integrator| {
+                    normalize_address(&swap.integrator) == *integrator
+                })
         }
+        EmojicoinDbEvent::Liquidity(liquidity) => {
+            is_at_least(&liquidity.quote_amount, filters.min_quote_volume)
+                && is_at_least(&liquidity.base_amount, filters.min_base_volume)
+        }
+        _ => true,
+    }
+}
+
+/// Returns true if the given subscription should receive the given event.
+pub fn is_match(subscription: &ClientSubscription, event: &EmojicoinDbEvent) -> bool {
+    match get_routing_key(event) {
+        Ok(key) => {
+            is_key_match(subscription, &key)
+                && is_address_match(subscription, get_addresses(event).as_deref())
+                && is_value_match(subscription, event)
+        }
+        Err(msg) => {
+            error!("{msg}");
+            false
+        }
+    }
+}
+
+/// Returns a new hard to guess connection ID, made of 128 random bits from the operating system.
+#[cfg_attr(not(feature = "sse"), allow(dead_code))]
+pub fn new_connection_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Returns a random duration up to `max`, to spread out what clients do at the same time.
+pub fn jitter(max: Duration) -> Duration {
+    let nanos = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+    Duration::from_nanos(rand::thread_rng().gen_range(0..=nanos))
+}
+
+/// Reads and parses an optional environment variable, falling back to `default` if it is not set.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Environment variable {name} is not valid.")),
+        Err(_) => default,
     }
 }
 
@@ -116,10 +280,16 @@ pub async fn shutdown_signal() -> Result<(), String> {
 
 impl From<SubscriptionMessage> for ClientSubscription {
     fn from(val: SubscriptionMessage) -> Self {
-        ClientSubscription {
+        let mut sub = ClientSubscription {
             arena: val.arena,
-            markets: HashSet::from_iter(val.markets),
+            // Empty lists mean all in legacy messages.
+            all_markets: val.markets.is_empty(),
+            markets: market_ids(&val.markets),
+            all_event_types: val.event_types.is_empty(),
             event_types: HashSet::from_iter(val.event_types),
+            addresses: normalize_addresses(&val.addresses),
+            filters: normalize_filters(val.filters),
+            conflation_ms: val.conflation_ms,
             market_candlestick_periods: match val.market_period {
                 Some(mp_request) => match mp_request {
                     MarketPeriodRequest::Subscribe { market_id, period } => {
@@ -141,17 +311,146 @@ impl From<SubscriptionMessage> for ClientSubscription {
                 },
                 None => HashSet::new(),
             },
+        };
+        apply_period_requests(
+            &mut sub,
+            val.market_period_requests,
+            val.arena_period_requests,
+        );
+        sub
+    }
+}
+
+impl From<SseSubscriptionQuery> for ClientSubscription {
+    fn from(val: SseSubscriptionQuery) -> Self {
+        ClientSubscription {
+            all_markets: val.markets.is_empty(),
+            markets: market_ids(&val.markets),
+            all_event_types: val.event_types.is_empty(),
+            event_types: HashSet::from_iter(val.event_types),
+            addresses: normalize_addresses(&val.addresses),
+            filters: normalize_filters(ValueFilter {
+                min_quote_volume: val.min_quote_volume,
+                min_base_volume: val.min_base_volume,
+                side: val.side,
+                state_transition: val.state_transition,
+                integrator: val.integrator,
+            }),
+            conflation_ms: val.conflation_ms,
+            market_candlestick_periods: val
+                .candles
+                .into_iter()
+                .flat_map(|candle| match candle.period {
+                    Some(period) => vec![(candle.market_id, period)],
+                    None => ALL_PERIODS
+                        .map(|period| (candle.market_id, period))
+                        .to_vec(),
+                })
+                .collect(),
+            arena: val.arena,
+            arena_candlestick_periods: HashSet::from_iter(val.arena_candles),
+        }
+    }
+}
+
+/// IDs of the given markets, ignoring the symbols that could not be resolved.
+fn market_ids(markets: &[MarketRef]) -> HashSet<u64> {
+    markets.iter().filter_map(MarketRef::id).collect()
+}
+
+fn normalize_addresses(addresses: &[String]) -> HashSet<String> {
+    addresses.iter().map(|a| normalize_address(a)).collect()
+}
+
+fn normalize_filters(filters: ValueFilter) -> ValueFilter {
+    ValueFilter {
+        integrator: filters.integrator.map(|a| normalize_address(&a)),
+        ..filters
+    }
+}
+
+/// Adds the candlesticks of the given markets and periods to the subscription.
+fn subscribe_candlesticks(sub: &mut ClientSubscription, candlesticks: MarketPeriods) {
+    let market_ids = candlesticks
+        .market_ids
+        .unwrap_or_else(|| sub.markets.iter().copied().collect());
+    let periods = candlesticks.periods.unwrap_or(ALL_PERIODS.to_vec());
+    for market_id in market_ids {
+        for period in &periods {
+            sub.market_candlestick_periods.insert((market_id, *period));
+        }
+    }
+}
+
+/// Removes the candlesticks of the given markets and periods from the subscription.
+///
+/// Unlike when subscribing, leaving the markets unset removes the periods from every market.
+fn unsubscribe_candlesticks(sub: &mut ClientSubscription, candlesticks: &MarketPeriods) {
+    sub.market_candlestick_periods
+        .retain(|(market_id, period)| {
+            let market_matches = candlesticks
+                .market_ids
+                .as_ref()
+                .map_or(true, |ids| ids.contains(market_id));
+            let period_matches = candlesticks
+                .periods
+                .as_ref()
+                .map_or(true, |periods| periods.contains(period));
+            !(market_matches && period_matches)
+        });
+}
+
+/// Applies list-valued candlestick period requests to the subscription, in order.
+fn apply_period_requests(
+    sub: &mut ClientSubscription,
+    market_periods: Vec<MarketPeriodsRequest>,
+    arena_periods: Vec<ArenaPeriodsRequest>,
+) {
+    for request in market_periods {
+        match request {
+            MarketPeriodsRequest::Subscribe(candlesticks) => {
+                subscribe_candlesticks(sub, candlesticks)
+            }
+            MarketPeriodsRequest::Unsubscribe(candlesticks) => {
+                unsubscribe_candlesticks(sub, &candlesticks)
+            }
+        }
+    }
+    for request in arena_periods {
+        match request {
+            ArenaPeriodsRequest::Subscribe { periods } => sub
+                .arena_candlestick_periods
+                .extend(periods.unwrap_or(ALL_PERIODS.to_vec())),
+            ArenaPeriodsRequest::Unsubscribe { periods: None } => {
+                sub.arena_candlestick_periods.clear()
+            }
+            ArenaPeriodsRequest::Unsubscribe {
+                periods: Some(periods),
+            } => {
+                for period in &periods {
+                    sub.arena_candlestick_periods.remove(period);
+                }
+            }
         }
     }
 }
 
 // Update the incoming subscription based on the text in the message received.
-#[allow(dead_code)]
+#[cfg(test)]
 pub fn update_subscription(
     current_sub_opt: &mut Option<ClientSubscription>,
     msg: &str,
-) -> Result<(), Error> {
+) -> Result<(), serde_json::Error> {
     let msg = serde_json::from_str::<SubscriptionMessage>(msg)?;
+    apply_subscription_message(current_sub_opt, msg);
+    Ok(())
+}
+
+// Update the incoming subscription based on an already parsed message.
+pub fn apply_subscription_message(
+    current_sub_opt: &mut Option<ClientSubscription>,
+    msg: SubscriptionMessage,
+) {
     match current_sub_opt {
         // Existing subscription; insert/remove from the existing arena candlestick periods.
         Some(current_sub) => {
@@ -180,28 +479,320 @@ pub fn update_subscription(
                 }
             }
             current_sub.arena = msg.arena;
-            current_sub.markets = HashSet::from_iter(msg.markets);
+            current_sub.all_markets = msg.markets.is_empty();
+            current_sub.markets = market_ids(&msg.markets);
+            current_sub.all_event_types = msg.event_types.is_empty();
             current_sub.event_types = HashSet::from_iter(msg.event_types);
+            current_sub.addresses = normalize_addresses(&msg.addresses);
+            current_sub.filters = normalize_filters(msg.filters);
+            current_sub.conflation_ms = msg.conflation_ms;
+            // Applied last, so that candlesticks requested for the subscription's markets are
+            // requested for the markets of this message.
+            apply_period_requests(
+                current_sub,
+                msg.market_period_requests,
+                msg.arena_period_requests,
+            );
         }
         _ => {
             // No current subscription; just convert the message into a new subscription.
             *current_sub_opt = Some(msg.into());
         }
     };
+}
+
+impl From<SubscriptionFilter> for ClientSubscription {
+    fn from(val: SubscriptionFilter) -> Self {
+        let mut sub = ClientSubscription {
+            all_markets: val.all_markets,
+            markets: market_ids(&val.markets),
+            all_event_types: val.all_event_types,
+            event_types: HashSet::from_iter(val.event_types),
+            addresses: normalize_addresses(&val.addresses),
+            filters: normalize_filters(val.filters),
+            conflation_ms: val.conflation_ms,
+            market_candlestick_periods: val
+                .market_periods
+                .into_iter()
+                .map(|mp| (mp.market_id, mp.period))
+                .collect(),
+            arena: val.arena,
+            arena_candlestick_periods: HashSet::from_iter(val.arena_periods),
+        };
+        for candlesticks in val.candlesticks {
+            subscribe_candlesticks(&mut sub, candlesticks);
+        }
+        sub
+    }
+}
+
+impl From<&ClientSubscription> for SubscriptionFilter {
+    fn from(val: &ClientSubscription) -> Self {
+        let mut markets: Vec<u64> = val.markets.iter().copied().collect();
+        markets.sort();
+        let markets = markets.into_iter().map(MarketRef::Id).collect();
+        let mut market_periods: Vec<MarketPeriod> = val
+            .market_candlestick_periods
+            .iter()
+            .map(|(market_id, period)| MarketPeriod {
+                market_id: *market_id,
+                period: *period,
+            })
+            .collect();
+        market_periods.sort_by_key(|mp| mp.market_id);
+        let mut addresses: Vec<String> = val.addresses.iter().cloned().collect();
+        addresses.sort();
+        SubscriptionFilter {
+            all_markets: val.all_markets,
+            markets,
+            all_event_types: val.all_event_types,
+            event_types: val.event_types.iter().copied().collect(),
+            addresses,
+            filters: val.filters.clone(),
+            conflation_ms: val.conflation_ms,
+            market_periods,
+            candlesticks: vec![],
+            arena: val.arena,
+            arena_periods: val.arena_candlestick_periods.iter().copied().collect(),
+        }
+    }
+}
+
+/// Checks the event types, addresses and periods listed in a client message, which would otherwise
+/// only be reported as a generic deserialization error.
+fn check_enum_values(value: &Value) -> Result<(), (ErrorCode, String)> {
+    let Some(object) = value.as_object() else {
+        if let Some(values) = value.as_array() {
+            return values.iter().try_for_each(check_enum_values);
+        }
+        return Ok(());
+    };
+    for (key, value) in object {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        match key.as_str() {
+            "event_types" => {
+                for event_type in values {
+                    if EmojicoinDbEventType::deserialize(event_type).is_err() {
+                        return Err((
+                            ErrorCode::UnknownEventType,
+                            format!("Unknown event type {event_type}."),
+                        ));
+                    }
+                }
+            }
+            "addresses" | "integrator" => {
+                for address in values {
+                    if !address.as_str().is_some_and(is_valid_address) {
+                        return Err((
+                            ErrorCode::InvalidAddress,
+                            format!("Invalid address {address}."),
+                        ));
+                    }
+                }
+            }
+            "period" | "periods" | "arena_periods" => {
+                for period in values {
+                    if Period::deserialize(period).is_err() {
+                        return Err((
+                            ErrorCode::UnknownPeriod,
+                            format!("Unknown period {period}."),
+                        ));
+                    }
+                }
+            }
+            _ => check_enum_values(value)?,
+        }
+    }
+    Ok(())
+}
+
+/// Parses a message sent by a websocket client.
+///
+/// Messages with a `version` are parsed as a [`SubscriptionRequest`], and other messages as a
+/// legacy [`SubscriptionMessage`].
+pub fn parse_client_message(msg: &str) -> Result<ClientRequest, ClientError> {
+    let value: Value = serde_json::from_str(msg)
+        .map_err(|e| ClientError::new(None, ErrorCode::BadJson, e.to_string()))?;
+    let id = value.get("id").cloned();
+    let error = |code, message: String| ClientError::new(id.clone(), code, message);
+    check_enum_values(&value).map_err(|(code, message)| error(code, message))?;
+    let message = match value.get("version") {
+        None => serde_json::from_value(value).map(ClientMessage::Legacy),
+        Some(version) if version.as_u64() == Some(PROTOCOL_VERSION.into()) => {
+            serde_json::from_value(value).map(ClientMessage::V1)
+        }
+        Some(version) => {
+            return Err(error(
+                ErrorCode::UnsupportedVersion,
+                format!("Unsupported protocol version {version}."),
+            ))
+        }
+    }
+    .map_err(|e| error(ErrorCode::InvalidMessage, e.to_string()))?;
+    Ok(ClientRequest { id, message })
+}
 
+/// Checks that the candlesticks requested without `market_ids` in a client message can be
+/// subscribed to.
+///
+/// They are requested for the markets of the resulting subscription, so the subscription must list
+/// its markets rather than cover all of them.
+pub fn check_candlestick_markets(
+    current_sub_opt: &Option<ClientSubscription>,
+    msg: &ClientMessage,
+) -> Result<(), String> {
+    let (all_markets, without_markets) = match msg {
+        ClientMessage::Legacy(msg) => (
+            // Empty lists mean all in legacy messages.
+            msg.markets.is_empty(),
+            msg.market_period_requests.iter().any(|request| {
+                matches!(
+                    request,
+                    MarketPeriodsRequest::Subscribe(MarketPeriods {
+                        market_ids: None,
+                        ..
+                    })
+                )
+            }),
+        ),
+        ClientMessage::V1(request) => match &request.action {
+            SubscriptionAction::Subscribe(filter) => (
+                filter.all_markets || current_sub_opt.as_ref().is_some_and(|sub| sub.all_markets),
+                filter.candlesticks.iter().any(|c| c.market_ids.is_none()),
+            ),
+            SubscriptionAction::Replace(filter) => (
+                filter.all_markets,
+                filter.candlesticks.iter().any(|c| c.market_ids.is_none()),
+            ),
+            SubscriptionAction::Unsubscribe(_) | SubscriptionAction::Clear => return Ok(()),
+        },
+    };
+    if all_markets && without_markets {
+        return Err(
+            "Candlesticks without market_ids can't be requested for all markets.".to_string(),
+        );
+    }
     Ok(())
 }
 
+// Update the incoming subscription based on a parsed client message of any version.
+pub fn apply_client_message(current_sub_opt: &mut Option<ClientSubscription>, msg: ClientMessage) {
+    match msg {
+        ClientMessage::Legacy(msg) => apply_subscription_message(current_sub_opt, msg),
+        ClientMessage::V1(request) => apply_subscription_action(current_sub_opt, request.action),
+    }
+}
+
+// Update the incoming subscription based on an explicit action.
+pub fn apply_subscription_action(
+    current_sub_opt: &mut Option<ClientSubscription>,
+    action: SubscriptionAction,
+) {
+    match action {
+        SubscriptionAction::Subscribe(filter) => {
+            let sub = current_sub_opt.get_or_insert_with(ClientSubscription::default);
+            sub.all_markets |= filter.all_markets;
+            sub.markets.extend(market_ids(&filter.markets));
+            sub.all_event_types |= filter.all_event_types;
+            sub.event_types.extend(filter.event_types);
+            sub.addresses.extend(normalize_addresses(&filter.addresses));
+            let filters = normalize_filters(filter.filters);
+            let current = &mut sub.filters;
+            current.min_quote_volume = filters.min_quote_volume.or(current.min_quote_volume);
+            current.min_base_volume = filters.min_base_volume.or(current.min_base_volume);
+            current.side = filters.side.or(current.side);
+            current.state_transition = filters.state_transition.or(current.state_transition);
+            current.integrator = filters.integrator.or(current.integrator.take());
+            sub.conflation_ms = filter.conflation_ms.or(sub.conflation_ms);
+            sub.market_candlestick_periods.extend(
+                filter
+                    .market_periods
+                    .into_iter()
+                    .map(|mp| (mp.market_id, mp.period)),
+            );
+            sub.arena |= filter.arena;
+            sub.arena_candlestick_periods.extend(filter.arena_periods);
+            for candlesticks in filter.candlesticks {
+                subscribe_candlesticks(sub, candlesticks);
+            }
+        }
+        SubscriptionAction::Unsubscribe(filter) => {
+            // Nothing to unsubscribe from.
+            let Some(sub) = current_sub_opt else {
+                return;
+            };
+            if filter.all_markets {
+                sub.all_markets = false;
+                sub.markets.clear();
+            }
+            for market_id in market_ids(&filter.markets) {
+                sub.markets.remove(&market_id);
+            }
+            if filter.all_event_types {
+                sub.all_event_types = false;
+                sub.event_types.clear();
+            }
+            for event_type in &filter.event_types {
+                sub.event_types.remove(event_type);
+            }
+            for address in &filter.addresses {
+                sub.addresses.remove(&normalize_address(address));
+            }
+            let current = &mut sub.filters;
+            let filters = &filter.filters;
+            if filters.min_quote_volume.is_some() {
+                current.min_quote_volume = None;
+            }
+            if filters.min_base_volume.is_some() {
+                current.min_base_volume = None;
+            }
+            if filters.side.is_some() {
+                current.side = None;
+            }
+            if filters.state_transition.is_some() {
+                current.state_transition = None;
+            }
+            if filters.integrator.is_some() {
+                current.integrator = None;
+            }
+            if filter.conflation_ms.is_some() {
+                sub.conflation_ms = None;
+            }
+            for mp in &filter.market_periods {
+                sub.market_candlestick_periods
+                    .remove(&(mp.market_id, mp.period));
+            }
+            if filter.arena {
+                sub.arena = false;
+            }
+            for period in &filter.arena_periods {
+                sub.arena_candlestick_periods.remove(period);
+            }
+            for candlesticks in &filter.candlesticks {
+                unsubscribe_candlesticks(sub, candlesticks);
+            }
+        }
+        SubscriptionAction::Replace(filter) => *current_sub_opt = Some(filter.into()),
+        SubscriptionAction::Clear => *current_sub_opt = None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use processor::emojicoin_dot_fun::Period;
+    use crate::types::SubscriptionRequest;
 
     #[test]
     fn test_ignore_unsubscribe_on_non_existent_sub() {
         let msg = SubscriptionMessage {
-            markets: vec![1, 2, 3],
+            markets: vec![1.into(), 2.into(), 3.into()],
             event_types: vec![EmojicoinDbEventType::Chat],
+            addresses: vec![],
+            filters: ValueFilter::default(),
+            conflation_ms: None,
             market_period: Some(MarketPeriodRequest::Subscribe {
                 market_id: 12,
                 period: Period::FifteenMinutes,
@@ -210,12 +801,22 @@ mod tests {
             arena_period: Some(ArenaPeriodRequest::Unsubscribe {
                 period: Period::FifteenMinutes,
             }),
+            market_period_requests: vec![],
+            arena_period_requests: vec![],
+            since: None,
+            epoch: None,
+            snapshot: false,
         };
         assert_eq!(
             ClientSubscription::from(msg),
             ClientSubscription {
+                all_markets: false,
                 markets: HashSet::from([1, 2, 3]),
+                all_event_types: false,
                 event_types: HashSet::from([EmojicoinDbEventType::Chat]),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_candlestick_periods: HashSet::from([(12, Period::FifteenMinutes)]),
                 arena: true,
                 arena_candlestick_periods: HashSet::new(),
@@ -227,8 +828,13 @@ mod tests {
     fn test_new_and_update_happy_path() {
         let market_periods_original_sub = HashSet::from([(3, Period::FiveMinutes)]);
         let subscription = &mut Some(ClientSubscription {
+            all_markets: false,
             markets: HashSet::from([1, 2, 3]),
+            all_event_types: false,
             event_types: HashSet::from([EmojicoinDbEventType::Chat]),
+            addresses: HashSet::new(),
+            filters: ValueFilter::default(),
+            conflation_ms: None,
             market_candlestick_periods: market_periods_original_sub.clone(),
             arena: true,
             arena_candlestick_periods: HashSet::from([Period::FiveMinutes]),
@@ -243,8 +849,13 @@ mod tests {
         assert_eq!(
             *subscription.as_ref().unwrap(),
             ClientSubscription {
+                all_markets: true,
                 markets: HashSet::new(),
+                all_event_types: true,
                 event_types: HashSet::new(),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_candlestick_periods: market_periods_original_sub.clone(),
                 arena: false,
                 arena_candlestick_periods: HashSet::new(),
@@ -271,8 +882,13 @@ mod tests {
         assert_eq!(
             *subscription.as_ref().unwrap(),
             ClientSubscription {
+                all_markets: false,
                 markets: HashSet::from([4, 2, 13, 14]),
+                all_event_types: false,
                 event_types: HashSet::from([EmojicoinDbEventType::MarketRegistration]),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_candlestick_periods: market_periods_original_sub,
                 arena: false,
                 arena_candlestick_periods: HashSet::from([Period::FifteenMinutes, Period::OneHour]),
@@ -283,8 +899,13 @@ mod tests {
     #[test]
     fn test_one_market_subscription() {
         let subscription = &mut Some(ClientSubscription {
+            all_markets: true,
             markets: HashSet::new(),
+            all_event_types: true,
             event_types: HashSet::new(),
+            addresses: HashSet::new(),
+            filters: ValueFilter::default(),
+            conflation_ms: None,
             market_candlestick_periods: HashSet::new(),
             arena: false,
             arena_candlestick_periods: HashSet::new(),
@@ -296,8 +917,13 @@ mod tests {
         assert_eq!(
             *subscription.as_mut().unwrap(),
             ClientSubscription {
+                all_markets: true,
                 markets: HashSet::new(),
+                all_event_types: true,
                 event_types: HashSet::new(),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_candlestick_periods: HashSet::from([(33, Period::OneHour)]),
                 arena: false,
                 arena_candlestick_periods: HashSet::new(),
@@ -308,8 +934,13 @@ mod tests {
     #[test]
     fn test_market_subscriptions_happy_path() {
         let subscription = &mut Some(ClientSubscription {
+            all_markets: false,
             markets: HashSet::from([1, 2, 3]),
+            all_event_types: false,
             event_types: HashSet::from([EmojicoinDbEventType::Chat]),
+            addresses: HashSet::new(),
+            filters: ValueFilter::default(),
+            conflation_ms: None,
             market_candlestick_periods: HashSet::from([(1234, Period::FiveMinutes)]),
             arena: true,
             arena_candlestick_periods: HashSet::from([Period::FiveMinutes]),
@@ -369,8 +1000,13 @@ mod tests {
             // Keep in mind it *always* overwrites `markets`, `event_types`, and `arena` with the
             // last message value, which uses defaults if nothing is there.
             ClientSubscription {
+                all_markets: true,
                 markets: HashSet::new(),
+                all_event_types: true,
                 event_types: HashSet::new(),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
                 market_candlestick_periods: HashSet::from([
                     (1234, Period::FiveMinutes),
                     (77, Period::OneMinute),
@@ -387,4 +1023,370 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_parse_client_message_versions() {
+        assert_eq!(
+            parse_client_message(r#"{ "markets": [1] }"#).unwrap(),
+            ClientRequest {
+                id: None,
+                message: ClientMessage::Legacy(SubscriptionMessage {
+                    markets: vec![1.into()],
+                    ..Default::default()
+                }),
+            }
+        );
+        assert_eq!(
+            parse_client_message(r#"{ "version": 1, "action": "clear", "since": 3, "id": 7 }"#)
+                .unwrap(),
+            ClientRequest {
+                id: Some(Value::from(7)),
+                message: ClientMessage::V1(SubscriptionRequest {
+                    version: 1,
+                    action: SubscriptionAction::Clear,
+                    since: Some(3),
+                    epoch: None,
+                    snapshot: false,
+                }),
+            }
+        );
+        assert!(parse_client_message(r#"{ "version": 1, "action": "merge" }"#).is_err());
+        assert!(parse_client_message(r#"{ "version": 1 }"#).is_err());
+    }
+
+    #[test]
+    fn test_subscription_actions() {
+        let subscription = &mut None;
+        let apply = |subscription: &mut Option<ClientSubscription>, msg: &str| {
+            apply_client_message(subscription, parse_client_message(msg).unwrap().message);
+        };
+
+        apply(
+            subscription,
+            r#"{
+              "version": 1,
+              "action": "subscribe",
+              "markets": [1, 2],
+              "event_types": ["Swap"],
+              "market_periods": [{ "market_id": 1, "period": "OneMinute" }]
+            }"#,
+        );
+        apply(
+            subscription,
+            r#"{ "version": 1, "action": "subscribe", "markets": [3], "arena": true }"#,
+        );
+        assert_eq!(
+            *subscription.as_ref().unwrap(),
+            ClientSubscription {
+                all_markets: false,
+                markets: HashSet::from([1, 2, 3]),
+                all_event_types: false,
+                event_types: HashSet::from([EmojicoinDbEventType::Swap]),
+                addresses: HashSet::new(),
+                filters: ValueFilter::default(),
+                conflation_ms: None,
+                market_candlestick_periods: HashSet::from([(1, Period::OneMinute)]),
+                arena: true,
+                arena_candlestick_periods: HashSet::new(),
+            }
+        );
+
+        // Unsubscribing from every market leaves no market covered, rather than all of them.
+        apply(
+            subscription,
+            r#"{
+              "version": 1,
+              "action": "unsubscribe",
+              "markets": [1, 2, 3],
+              "market_periods": [{ "market_id": 1, "period": "OneMinute" }]
+            }"#,
+        );
+        let sub = subscription.as_ref().unwrap();
+        assert!(sub.markets.is_empty());
+        assert!(!is_key_match(
+            sub,
+            &RoutingKey::Market(EmojicoinDbEventType::Swap, 1)
+        ));
+        assert!(is_key_match(sub, &RoutingKey::Arena));
+
+        apply(
+            subscription,
+            r#"{ "version": 1, "action": "replace", "all_markets": true, "all_event_types": true }"#,
+        );
+        let sub = subscription.as_ref().unwrap();
+        assert!(!sub.arena);
+        assert!(is_key_match(
+            sub,
+            &RoutingKey::Market(EmojicoinDbEventType::Chat, 42)
+        ));
+        assert!(is_key_match(
+            sub,
+            &RoutingKey::Global(EmojicoinDbEventType::GlobalState)
+        ));
+
+        apply(
+            subscription,
+            r#"{ "version": 1, "action": "unsubscribe", "all_event_types": true }"#,
+        );
+        assert!(!is_key_match(
+            subscription.as_ref().unwrap(),
+            &RoutingKey::Market(EmojicoinDbEventType::Chat, 42)
+        ));
+
+        apply(subscription, r#"{ "version": 1, "action": "clear" }"#);
+        assert!(subscription.is_none());
+
+        // Legacy messages still work after versioned ones.
+        apply(subscription, r#"{ "markets": [5] }"#);
+        assert!(subscription.as_ref().unwrap().all_event_types);
+    }
+
+    #[test]
+    fn test_parse_client_message_errors() {
+        let code = |msg| parse_client_message(msg).unwrap_err().code;
+        assert_eq!(code(r#"{ "markets": [1"#), ErrorCode::BadJson);
+        assert_eq!(code(r#"{ "markets": "all" }"#), ErrorCode::InvalidMessage);
+        assert_eq!(
+            code(r#"{ "event_types": ["Swap", "Trade"] }"#),
+            ErrorCode::UnknownEventType
+        );
+        assert_eq!(
+            code(r#"{ "arena_period": { "action": "subscribe", "period": "TwoHours" } }"#),
+            ErrorCode::UnknownPeriod
+        );
+        assert_eq!(
+            code(r#"{ "version": 1, "action": "subscribe", "arena_periods": ["TwoHours"] }"#),
+            ErrorCode::UnknownPeriod
+        );
+        assert_eq!(
+            code(r#"{ "version": 2, "action": "clear" }"#),
+            ErrorCode::UnsupportedVersion
+        );
+
+        // The request id is kept when it can be read.
+        let error = parse_client_message(r#"{ "id": "a", "event_types": ["Trade"] }"#).unwrap_err();
+        assert_eq!(error.id, Some(Value::from("a")));
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(normalize_address("0x000ABC"), "0xabc");
+        assert_eq!(normalize_address("0x000"), "0x0");
+        assert!(is_valid_address("0xABC"));
+        assert!(!is_valid_address("0xxyz"));
+        assert!(!is_valid_address("abc"));
+
+        let subscription = &mut None;
+        assert!(update_subscription(subscription, r#"{ "addresses": ["0x00AbC"] }"#).is_ok());
+        let sub = subscription.as_ref().unwrap();
+        assert_eq!(sub.addresses, HashSet::from(["0xabc".to_string()]));
+        assert!(is_address_match(sub, Some(&["0x1".into(), "0xabc".into()])));
+        assert!(!is_address_match(sub, Some(&["0x1".into()])));
+        assert!(is_address_match(sub, None));
+
+        assert_eq!(
+            parse_client_message(r#"{ "addresses": ["xyz"] }"#)
+                .unwrap_err()
+                .code,
+            ErrorCode::InvalidAddress
+        );
+    }
+
+    #[test]
+    fn test_value_filters() {
+        assert!(is_at_least(&5u64, None));
+        assert!(is_at_least(&5u64, Some(5)));
+        assert!(!is_at_least(&4u64, Some(5)));
+
+        let subscription = &mut None;
+        let mut apply = |msg| {
+            let request = parse_client_message(msg).unwrap();
+            apply_client_message(subscription, request.message);
+            subscription.as_ref().unwrap().filters.clone()
+        };
+        assert_eq!(
+            apply(r#"{ "filters": { "min_quote_volume": 100, "integrator": "0x00AB" } }"#),
+            ValueFilter {
+                min_quote_volume: Some(100),
+                integrator: Some("0xab".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            apply(r#"{ "version": 1, "action": "subscribe", "filters": { "side": "sell" } }"#),
+            ValueFilter {
+                min_quote_volume: Some(100),
+                side: Some(Side::Sell),
+                integrator: Some("0xab".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            apply(
+                r#"{ "version": 1, "action": "unsubscribe", "filters": { "min_quote_volume": 0, "integrator": "0x1" } }"#
+            ),
+            ValueFilter {
+                side: Some(Side::Sell),
+                ..Default::default()
+            }
+        );
+        // Legacy messages replace the filters.
+        assert_eq!(
+            apply(r#"{ "filters": { "state_transition": true } }"#),
+            ValueFilter {
+                state_transition: Some(true),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            parse_client_message(r#"{ "filters": { "integrator": "abc" } }"#)
+                .unwrap_err()
+                .code,
+            ErrorCode::InvalidAddress
+        );
+    }
+
+    #[test]
+    fn test_conflation_window() {
+        let subscription = &mut None;
+        let mut apply = |msg| {
+            let request = parse_client_message(msg).unwrap();
+            apply_client_message(subscription, request.message);
+            subscription.as_ref().unwrap().conflation_ms
+        };
+        assert_eq!(
+            apply(r#"{ "markets": [1], "conflation_ms": 500 }"#),
+            Some(500)
+        );
+        assert_eq!(
+            apply(r#"{ "version": 1, "action": "subscribe", "markets": [2] }"#),
+            Some(500)
+        );
+        assert_eq!(
+            apply(r#"{ "version": 1, "action": "unsubscribe", "conflation_ms": 0 }"#),
+            None
+        );
+        assert_eq!(
+            apply(r#"{ "version": 1, "action": "subscribe", "conflation_ms": 250 }"#),
+            Some(250)
+        );
+    }
+
+    #[test]
+    fn test_list_valued_periods() {
+        let subscription = &mut None;
+        assert!(update_subscription(
+            subscription,
+            r#"{
+              "markets": [1, 2],
+              "market_period_requests": [
+                { "action": "subscribe", "periods": ["OneMinute", "FiveMinutes"] },
+                { "action": "subscribe", "market_ids": [3] }
+              ],
+              "arena_period_requests": [{ "action": "subscribe", "periods": ["OneHour", "OneDay"] }]
+            }"#,
+        )
+        .is_ok());
+        let sub = subscription.as_ref().unwrap();
+        assert_eq!(
+            sub.market_candlestick_periods,
+            [
+                (1, Period::OneMinute),
+                (1, Period::FiveMinutes),
+                (2, Period::OneMinute),
+                (2, Period::FiveMinutes),
+            ]
+            .into_iter()
+            .chain(ALL_PERIODS.map(|period| (3, period)))
+            .collect()
+        );
+        assert_eq!(
+            sub.arena_candlestick_periods,
+            HashSet::from([Period::OneHour, Period::OneDay])
+        );
+
+        assert!(update_subscription(
+            subscription,
+            r#"{
+              "markets": [1, 2],
+              "market_period_requests": [
+                { "action": "unsubscribe", "periods": ["OneMinute"] },
+                { "action": "unsubscribe", "market_ids": [3] }
+              ],
+              "arena_period_requests": [{ "action": "unsubscribe" }]
+            }"#,
+        )
+        .is_ok());
+        let sub = subscription.as_ref().unwrap();
+        assert_eq!(
+            sub.market_candlestick_periods,
+            HashSet::from([(1, Period::FiveMinutes), (2, Period::FiveMinutes)])
+        );
+        assert!(sub.arena_candlestick_periods.is_empty());
+
+        apply_client_message(
+            subscription,
+            parse_client_message(
+                r#"{
+                  "version": 1,
+                  "action": "replace",
+                  "markets": [4],
+                  "candlesticks": [{ "periods": ["FifteenSeconds"] }]
+                }"#,
+            )
+            .unwrap()
+            .message,
+        );
+        assert_eq!(
+            subscription.as_ref().unwrap().market_candlestick_periods,
+            HashSet::from([(4, Period::FifteenSeconds)])
+        );
+
+        let code = |msg| parse_client_message(msg).unwrap_err().code;
+        assert_eq!(
+            code(
+                r#"{ "market_period_requests": [{ "action": "subscribe", "periods": ["TwoHours"] }] }"#
+            ),
+            ErrorCode::UnknownPeriod
+        );
+        assert_eq!(
+            code(
+                r#"{ "arena_period_requests": [{ "action": "unsubscribe", "periods": ["TwoHours"] }] }"#
+            ),
+            ErrorCode::UnknownPeriod
+        );
+
+        // Candlesticks can't be requested for the markets of a subscription to all markets.
+        let check = |sub: &Option<ClientSubscription>, msg| {
+            check_candlestick_markets(sub, &parse_client_message(msg).unwrap().message)
+        };
+        assert!(check(
+            &None,
+            r#"{ "market_period_requests": [{ "action": "subscribe", "periods": ["OneMinute"] }] }"#
+        )
+        .is_err());
+        assert!(check(
+            &None,
+            r#"{ "market_period_requests": [{ "action": "subscribe", "market_ids": [1] }] }"#
+        )
+        .is_ok());
+        let candlesticks = r#"{
+          "version": 1,
+          "action": "subscribe",
+          "markets": [1],
+          "candlesticks": [{ "periods": ["OneMinute"] }]
+        }"#;
+        assert!(check(&None, candlesticks).is_ok());
+        let all_markets = Some(ClientSubscription {
+            all_markets: true,
+            ..Default::default()
+        });
+        assert!(check(&all_markets, candlesticks).is_err());
+        assert!(check(
+            &None,
+            r#"{ "version": 1, "action": "replace", "all_markets": true, "candlesticks": [{}] }"#
+        )
+        .is_err());
+    }
 }